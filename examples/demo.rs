@@ -4,8 +4,8 @@ use bevy::{
     window::PresentMode,
 };
 use bevy_oit::{
-    material::{OitMaterial, OitMaterialMeshBundle},
-    OitCamera, OitPlugin,
+    material::{OitMaterialMeshBundle, StandardOitMaterial},
+    OitCamera, OitDebugView, OitPlugin,
 };
 use utils::{
     camera_controller::{CameraController, CameraControllerPlugin},
@@ -29,7 +29,10 @@ fn main() {
             OitPlugin,
         ))
         .add_systems(Startup, setup)
-        .add_systems(Update, (update_scene_material, toggle_material))
+        .add_systems(
+            Update,
+            (update_scene_material, toggle_material, cycle_debug_view),
+        )
         .run();
 }
 
@@ -40,7 +43,7 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     asset_server: Res<AssetServer>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
 ) {
     // camera
     commands.spawn((
@@ -88,24 +91,27 @@ fn setup(
     let sphere_handle = meshes.add(UVSphere::default().into());
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::RED.with_a(0.75),
+            ..default()
         }),
         transform: Transform::from_xyz(-1., 0., 0.),
         ..default()
     });
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::RED.with_a(0.5),
+            ..default()
         }),
         transform: Transform::from_xyz(0., 0., 0.),
         ..default()
     });
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::RED.with_a(0.1),
+            ..default()
         }),
         transform: Transform::from_xyz(1., 0., 0.),
         ..default()
@@ -115,14 +121,15 @@ fn setup(
 fn update_scene_material(
     mut commands: Commands,
     q: Query<Entity, (With<Handle<StandardMaterial>>, Without<KeepMaterial>)>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
 ) {
     for e in &q {
         commands
             .entity(e)
             .remove::<Handle<StandardMaterial>>()
-            .insert(oit_materials.add(OitMaterial {
+            .insert(oit_materials.add(StandardOitMaterial {
                 base_color: Color::WHITE.with_a(0.25),
+                ..default()
             }));
     }
 }
@@ -133,11 +140,11 @@ fn toggle_material(
     q: Query<(
         Entity,
         Option<&Handle<GoochMaterial>>,
-        Option<&Handle<OitMaterial>>,
+        Option<&Handle<StandardOitMaterial>>,
     )>,
     keyboard_input: Res<Input<KeyCode>>,
     mut gooch_materials: ResMut<Assets<GoochMaterial>>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
     mut text: Query<&mut Text>,
     mut oit_enabled: Local<bool>,
 ) {
@@ -158,15 +165,16 @@ fn toggle_material(
                 commands
                     .entity(e)
                     .remove::<Handle<GoochMaterial>>()
-                    .insert(oit_materials.add(OitMaterial {
+                    .insert(oit_materials.add(StandardOitMaterial {
                         base_color: gooch.base_color,
+                        ..default()
                     }));
             }
         } else if let Some(handle) = oit {
             if let Some(oit) = oit_materials.get(handle) {
                 commands
                     .entity(e)
-                    .remove::<Handle<OitMaterial>>()
+                    .remove::<Handle<StandardOitMaterial>>()
                     .insert(gooch_materials.add(GoochMaterial {
                         base_color: oit.base_color,
                         depth_bias: 0.0,
@@ -175,3 +183,19 @@ fn toggle_material(
         }
     }
 }
+
+fn cycle_debug_view(keyboard_input: Res<Input<KeyCode>>, mut cameras: Query<&mut OitCamera>) {
+    if !keyboard_input.just_pressed(KeyCode::V) {
+        return;
+    }
+
+    for mut oit_camera in &mut cameras {
+        oit_camera.debug_view = match oit_camera.debug_view {
+            OitDebugView::None => OitDebugView::LayerCount,
+            OitDebugView::LayerCount => OitDebugView::Overflow,
+            OitDebugView::Overflow => OitDebugView::DepthComplexity,
+            OitDebugView::DepthComplexity => OitDebugView::None,
+        };
+        info!("OIT debug view: {:?}", oit_camera.debug_view);
+    }
+}
@@ -4,7 +4,7 @@ use bevy::{
     window::PresentMode,
 };
 use bevy_oit::{
-    material::{OitMaterial, OitMaterialMeshBundle},
+    material::{OitMaterialMeshBundle, StandardOitMaterial},
     OitCamera, OitPlugin,
 };
 use rand::Rng;
@@ -40,7 +40,7 @@ struct KeepMaterial;
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
 ) {
     commands.spawn((
         Camera3dBundle {
@@ -76,13 +76,14 @@ fn setup(
             for z in 0..=size {
                 spheres.push(OitMaterialMeshBundle {
                     mesh: sphere_handle.clone(),
-                    material: oit_materials.add(OitMaterial {
+                    material: oit_materials.add(StandardOitMaterial {
                         base_color: Color::rgba(
                             rng.gen_range(0.0..1.0),
                             rng.gen_range(0.0..1.0),
                             rng.gen_range(0.0..1.0),
                             0.5,
                         ),
+                        ..default()
                     }),
                     transform: Transform::from_xyz(
                         (x as f32 - size as f32 / 2.0) * offset,
@@ -103,11 +104,11 @@ fn toggle_material(
     q: Query<(
         Entity,
         Option<&Handle<GoochMaterial>>,
-        Option<&Handle<OitMaterial>>,
+        Option<&Handle<StandardOitMaterial>>,
     )>,
     keyboard_input: Res<Input<KeyCode>>,
     mut gooch_materials: ResMut<Assets<GoochMaterial>>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
     mut text: Query<&mut Text>,
     mut oit_enabled: Local<bool>,
 ) {
@@ -128,15 +129,16 @@ fn toggle_material(
                 commands
                     .entity(e)
                     .remove::<Handle<GoochMaterial>>()
-                    .insert(oit_materials.add(OitMaterial {
+                    .insert(oit_materials.add(StandardOitMaterial {
                         base_color: gooch.base_color,
+                        ..default()
                     }));
             }
         } else if let Some(handle) = oit {
             if let Some(oit) = oit_materials.get(handle) {
                 commands
                     .entity(e)
-                    .remove::<Handle<OitMaterial>>()
+                    .remove::<Handle<StandardOitMaterial>>()
                     .insert(gooch_materials.add(GoochMaterial {
                         base_color: oit.base_color,
                         depth_bias: 0.0,
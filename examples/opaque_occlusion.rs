@@ -5,7 +5,7 @@ use bevy::{
     window::PresentMode,
 };
 use bevy_oit::{
-    material::{OitMaterial, OitMaterialMeshBundle},
+    material::{OitMaterialMeshBundle, StandardOitMaterial},
     OitCamera, OitPlugin,
 };
 use utils::camera_controller::{CameraController, CameraControllerPlugin};
@@ -38,7 +38,7 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut std_materials: ResMut<Assets<StandardMaterial>>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
 ) {
     commands.spawn((
         Camera3dBundle {
@@ -88,8 +88,9 @@ fn setup(
         .insert(KeepMaterial);
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::RED.with_a(0.5),
+            ..default()
         }),
         transform: Transform::from_xyz(x, 0., 0.),
         ..default()
@@ -106,8 +107,9 @@ fn setup(
         .insert(KeepMaterial);
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::RED.with_a(0.5),
+            ..default()
         }),
         transform: Transform::from_xyz(0., 0., 0.),
         ..default()
@@ -125,8 +127,9 @@ fn setup(
         .insert(KeepMaterial);
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::RED.with_a(0.5),
+            ..default()
         }),
         transform: Transform::from_xyz(x, 0., 0.),
         ..default()
@@ -136,14 +139,15 @@ fn setup(
 fn mat(
     mut commands: Commands,
     q: Query<Entity, (With<Handle<StandardMaterial>>, Without<KeepMaterial>)>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
 ) {
     for e in &q {
         commands
             .entity(e)
             .remove::<Handle<StandardMaterial>>()
-            .insert(oit_materials.add(OitMaterial {
+            .insert(oit_materials.add(StandardOitMaterial {
                 base_color: Color::WHITE.with_a(0.1),
+                ..default()
             }));
     }
 }
@@ -171,11 +175,11 @@ fn toggle_material(
     q: Query<(
         Entity,
         Option<&Handle<GoochMaterial>>,
-        Option<&Handle<OitMaterial>>,
+        Option<&Handle<StandardOitMaterial>>,
     )>,
     keyboard_input: Res<Input<KeyCode>>,
     mut gooch_materials: ResMut<Assets<GoochMaterial>>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
     mut text: Query<&mut Text>,
     mut oit_enabled: Local<bool>,
 ) {
@@ -196,15 +200,16 @@ fn toggle_material(
                 commands
                     .entity(e)
                     .remove::<Handle<GoochMaterial>>()
-                    .insert(oit_materials.add(OitMaterial {
+                    .insert(oit_materials.add(StandardOitMaterial {
                         base_color: gooch.base_color,
+                        ..default()
                     }));
             }
         } else if let Some(handle) = oit {
             if let Some(oit) = oit_materials.get(handle) {
                 commands
                     .entity(e)
-                    .remove::<Handle<OitMaterial>>()
+                    .remove::<Handle<StandardOitMaterial>>()
                     .insert(gooch_materials.add(GoochMaterial {
                         base_color: oit.base_color,
                     }));
@@ -0,0 +1,66 @@
+use bevy::{prelude::*, sprite::Mesh2dHandle};
+use bevy_oit::{
+    material_2d::{ColorOitMaterial, OitMaterialMesh2dBundle},
+    OitCamera, OitDepthBias, OitPlugin,
+};
+
+fn main() {
+    App::new()
+        .insert_resource(Msaa::Sample4)
+        .add_plugins((DefaultPlugins, OitPlugin))
+        .add_systems(Startup, setup)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorOitMaterial>>,
+) {
+    commands.spawn((Camera2dBundle::default(), OitCamera::default()));
+
+    let quad: Mesh2dHandle = meshes
+        .add(shape::Quad::new(Vec2::splat(200.0)).into())
+        .into();
+
+    // The quads are spawned in a different order than their z so they would be blended in the
+    // wrong order without OIT
+    for (i, (color, z)) in [
+        (Color::RED, 2.0),
+        (Color::GREEN, 0.0),
+        (Color::BLUE, 3.0),
+        (Color::YELLOW, 1.0),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let offset = Vec2::new(-150.0 + i as f32 * 100.0, (i % 2) as f32 * 80.0 - 40.0);
+        commands.spawn(OitMaterialMesh2dBundle {
+            mesh: quad.clone(),
+            material: materials.add(color.with_a(0.5).into()),
+            transform: Transform::from_translation(offset.extend(z)),
+            ..default()
+        });
+    }
+
+    // The label has the same z as the panel behind it, the depth bias keeps it in front
+    commands.spawn(OitMaterialMesh2dBundle {
+        mesh: meshes
+            .add(shape::Quad::new(Vec2::new(300.0, 100.0)).into())
+            .into(),
+        material: materials.add(Color::WHITE.with_a(0.3).into()),
+        transform: Transform::from_xyz(0.0, -200.0, 4.0),
+        ..default()
+    });
+    commands.spawn((
+        OitMaterialMesh2dBundle {
+            mesh: meshes
+                .add(shape::Quad::new(Vec2::new(200.0, 40.0)).into())
+                .into(),
+            material: materials.add(Color::CYAN.with_a(0.8).into()),
+            transform: Transform::from_xyz(0.0, -200.0, 4.0),
+            ..default()
+        },
+        OitDepthBias(0.1),
+    ));
+}
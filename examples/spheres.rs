@@ -4,7 +4,7 @@ use bevy::{
     window::PresentMode,
 };
 use bevy_oit::{
-    material::{OitMaterial, OitMaterialMeshBundle},
+    material::{OitMaterialMeshBundle, StandardOitMaterial},
     OitCamera, OitPlugin,
 };
 use utils::{
@@ -40,7 +40,7 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<GoochMaterial>>,
-    mut oit_materials: ResMut<Assets<OitMaterial>>,
+    mut oit_materials: ResMut<Assets<StandardOitMaterial>>,
 ) {
     commands.spawn((
         Camera3dBundle {
@@ -70,24 +70,27 @@ fn setup(
     // oit material
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::RED.with_a(alpha),
+            ..default()
         }),
         transform: Transform::from_translation(pos_a - offset),
         ..default()
     });
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::GREEN.with_a(alpha),
+            ..default()
         }),
         transform: Transform::from_translation(pos_b - offset),
         ..default()
     });
     commands.spawn(OitMaterialMeshBundle {
         mesh: sphere_handle.clone(),
-        material: oit_materials.add(OitMaterial {
+        material: oit_materials.add(StandardOitMaterial {
             base_color: Color::BLUE.with_a(alpha),
+            ..default()
         }),
         transform: Transform::from_translation(pos_c - offset),
         ..default()
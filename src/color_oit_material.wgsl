@@ -0,0 +1,36 @@
+#import bevy_oit::oit_draw oit_draw, OitFragmentOutput
+#import bevy_sprite::mesh2d_view_bindings view
+#import bevy_sprite::mesh2d_vertex_output MeshVertexOutput
+#import bevy_core_pipeline::tonemapping screen_space_dither, powsafe, tone_mapping
+
+@group(1) @binding(0)
+var<uniform> color: vec4<f32>;
+@group(1) @binding(1)
+var color_texture: texture_2d<f32>;
+@group(1) @binding(2)
+var color_sampler: sampler;
+
+@fragment
+fn fragment(
+    @builtin(sample_mask) sample_mask: u32,
+    in: MeshVertexOutput,
+) -> OitFragmentOutput {
+    var output_color = color;
+#ifdef VERTEX_COLORS
+    output_color *= in.color;
+#endif
+#ifdef OIT_TEXTURE
+    output_color *= textureSample(color_texture, color_sampler, in.uv);
+#endif
+
+#ifdef TONEMAP_IN_SHADER
+    output_color = tone_mapping(output_color, view.color_grading);
+#ifdef DEBAND_DITHER
+    var color_rgb = powsafe(output_color.rgb, 1.0 / 2.2);
+    color_rgb = color_rgb + screen_space_dither(in.position.xy);
+    output_color = vec4(powsafe(color_rgb, 2.2), output_color.a);
+#endif
+#endif
+
+    return oit_draw(in.position, output_color, sample_mask);
+}
@@ -0,0 +1,60 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_component::ExtractComponent,
+        render_resource::{DynamicUniformBuffer, ShaderType},
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+
+/// Moves the transparent fragments of an entity toward the camera when the OIT layers are
+/// sorted, so coplanar surfaces like decals on glass or labels on panels don't z-fight.
+///
+/// The value is a distance in view space, in the same units as the [`Transform`] of the entity,
+/// and negative values move the fragments away from the camera. The fragments are still drawn at
+/// their position, only their order changes. The bias needs to be bigger than the precision of
+/// the depth stored in the layers, which loses 8 bits with an `hdr` camera, the coverage bits
+/// with MSAA and is a `f16` with the [`OitCamera::k_buffer`](crate::OitCamera::k_buffer).
+/// It shouldn't move the fragments behind the camera.
+///
+/// Only used by [`OitMethod::ABuffer`], [`OitMethod::MultiLayerAlphaBlending`] and
+/// [`OitMethod::LinkedList`], which store the fragments in layers.
+///
+/// [`OitMethod::ABuffer`]: crate::OitMethod::ABuffer
+/// [`OitMethod::MultiLayerAlphaBlending`]: crate::OitMethod::MultiLayerAlphaBlending
+/// [`OitMethod::LinkedList`]: crate::OitMethod::LinkedList
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, ExtractComponent)]
+pub struct OitDepthBias(pub f32);
+
+#[derive(ShaderType, Clone, Copy, Default)]
+pub struct OitDrawUniform {
+    pub depth_bias: f32,
+}
+
+/// The [`OitDrawUniform`] of every entity with an [`OitDepthBias`].
+///
+/// The first uniform has no bias and is used by all the other entities.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct OitDrawUniforms(pub DynamicUniformBuffer<OitDrawUniform>);
+
+/// The offset of the [`OitDrawUniform`] of the entity in [`OitDrawUniforms`].
+#[derive(Component, Clone, Copy)]
+pub struct OitDrawUniformOffset(pub u32);
+
+pub fn prepare_draw_uniforms(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut uniforms: ResMut<OitDrawUniforms>,
+    entities: Query<(Entity, &OitDepthBias)>,
+) {
+    uniforms.clear();
+    uniforms.push(OitDrawUniform::default());
+    for (entity, depth_bias) in &entities {
+        let offset = uniforms.push(OitDrawUniform {
+            depth_bias: depth_bias.0,
+        });
+        commands.entity(entity).insert(OitDrawUniformOffset(offset));
+    }
+    uniforms.write_buffer(&render_device, &render_queue);
+}
@@ -0,0 +1,63 @@
+use bevy::{
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_resource::{
+            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::RenderDevice,
+        texture::{CachedTexture, TextureCache},
+    },
+};
+
+use crate::OitCamera;
+
+/// Stores the depth of the transparent fragments, with the same values as the view depth
+pub const DEPTH_OUTPUT_TEXTURE_FORMAT: TextureFormat = TextureFormat::R32Float;
+
+/// The depth of the transparent fragments of an [`OitCamera`] using
+/// [`OitCamera::depth_output`].
+///
+/// This is added to the view in the render world so the passes running after the OIT node can
+/// read it. It isn't multisampled and pixels without transparent fragments are 0, the far plane
+/// of bevy's reverse-z projections.
+#[derive(Component)]
+pub struct OitDepthOutputTexture(pub CachedTexture);
+
+pub fn prepare_depth_output_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    views: Query<(Entity, &ExtractedCamera, &OitCamera)>,
+) {
+    for (entity, camera, oit_camera) in &views {
+        if oit_camera.depth_output.is_none() || !oit_camera.method.uses_layers() {
+            continue;
+        }
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+
+        let texture = texture_cache.get(
+            &render_device,
+            TextureDescriptor {
+                label: Some("oit_depth_output_texture"),
+                size: Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: DEPTH_OUTPUT_TEXTURE_FORMAT,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+        );
+
+        commands
+            .entity(entity)
+            .insert(OitDepthOutputTexture(texture));
+    }
+}
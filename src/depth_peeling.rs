@@ -0,0 +1,386 @@
+use bevy::{
+    asset::load_internal_asset,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_resource::{
+            BindGroup, BindGroupLayout, BlendComponent, BlendFactor, BlendOperation, BlendState,
+            CachedRenderPipelineId, ColorTargetState, ColorWrites, CompareFunction, DepthBiasState,
+            DepthStencilState, Extent3d, MultisampleState, PipelineCache, RenderPipelineDescriptor,
+            ShaderStages, SpecializedRenderPipeline, SpecializedRenderPipelines, StencilState,
+            TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages,
+        },
+        renderer::RenderDevice,
+        texture::{BevyDefault, CachedTexture, TextureCache},
+        view::{ExtractedView, ViewDepthTexture, ViewTarget},
+        Render, RenderApp, RenderSet,
+    },
+    utils::HashSet,
+};
+
+use crate::{
+    pipeline::OitLayersBindGroupLayout,
+    utils::{
+        bind_group_layout_types::{texture_2d, texture_depth_2d},
+        BindingResouceExt, RenderDeviceExt, RenderPipelineDescriptorBuilder,
+    },
+    OitCamera, OitMethod, OIT_DEPTH_PEELING_COMPOSITE_SHADER_HANDLE,
+};
+
+/// Renders the [`OitCamera`]s using [`OitMethod::DepthPeeling`]
+pub struct OitDepthPeelingPlugin;
+impl Plugin for OitDepthPeelingPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            OIT_DEPTH_PEELING_COMPOSITE_SHADER_HANDLE,
+            "depth_peeling_composite.wgsl",
+            Shader::from_wgsl
+        );
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<SpecializedRenderPipelines<OitDepthPeelingCompositePipeline>>()
+            .add_systems(
+                Render,
+                (
+                    prepare_depth_peeling_textures.in_set(RenderSet::Prepare),
+                    queue_depth_peeling_bind_groups.in_set(RenderSet::Queue),
+                    queue_depth_peeling_pipelines.in_set(RenderSet::Queue),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.init_resource::<OitDepthPeelingCompositePipeline>();
+    }
+}
+
+/// Stores the nearest fragment of a peel, and the peels blended together
+pub const PEEL_COLOR_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+pub const PEEL_DEPTH_TEXTURE_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+
+/// The render targets used by [`OitMethod::DepthPeeling`]
+///
+/// The peels alternate between the two depth textures so each one can test against the depth of
+/// the previous one.
+#[derive(Component)]
+pub struct OitDepthPeelingTextures {
+    pub color: CachedTexture,
+    pub depth: [CachedTexture; 2],
+    pub accumulation: CachedTexture,
+    /// The first sample of the depth of the view when it's multisampled
+    pub opaque_depth: Option<CachedTexture>,
+}
+
+pub fn prepare_depth_peeling_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    views: Query<(Entity, &ExtractedCamera, &OitCamera)>,
+    msaa: Res<Msaa>,
+) {
+    for (entity, camera, oit_camera) in &views {
+        let OitMethod::DepthPeeling { .. } = oit_camera.method else {
+            continue;
+        };
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+
+        // The peels are rendered without MSAA, the opaque depth is copied from the first sample
+        let mut descriptor = TextureDescriptor {
+            label: Some("oit_peel_color_texture"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: PEEL_COLOR_TEXTURE_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        };
+        let color = texture_cache.get(&render_device, descriptor.clone());
+
+        descriptor.label = Some("oit_peel_accumulation_texture");
+        let accumulation = texture_cache.get(&render_device, descriptor.clone());
+
+        descriptor.format = PEEL_DEPTH_TEXTURE_FORMAT;
+        descriptor.label = Some("oit_peel_depth_texture_a");
+        let depth_a = texture_cache.get(&render_device, descriptor.clone());
+        descriptor.label = Some("oit_peel_depth_texture_b");
+        let depth_b = texture_cache.get(&render_device, descriptor.clone());
+
+        let opaque_depth = (msaa.samples() > 1).then(|| {
+            descriptor.label = Some("oit_peel_opaque_depth_texture");
+            texture_cache.get(&render_device, descriptor)
+        });
+
+        commands.entity(entity).insert(OitDepthPeelingTextures {
+            color,
+            depth: [depth_a, depth_b],
+            accumulation,
+            opaque_depth,
+        });
+    }
+}
+
+#[derive(Component)]
+pub struct OitDepthPeelingBindGroups {
+    /// The depth textures used by the draw pass of the even and odd peels
+    pub peels: [BindGroup; 2],
+    /// Reads the color of the last peel
+    pub color: BindGroup,
+    /// Reads the peels blended together
+    pub accumulation: BindGroup,
+    /// Reads the multisampled depth of the view to copy its first sample
+    pub copy_depth: Option<BindGroup>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn queue_depth_peeling_bind_groups(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    layouts: Res<OitLayersBindGroupLayout>,
+    composite_pipeline: Res<OitDepthPeelingCompositePipeline>,
+    views: Query<(Entity, &OitDepthPeelingTextures, &ViewDepthTexture)>,
+    mut missing_depth_binding: Local<HashSet<Entity>>,
+) {
+    for (entity, textures, depth) in &views {
+        // The fragments are tested against the opaque depth in the shader, or against its copy
+        // which is made in a shader too
+        if !depth
+            .texture
+            .usage()
+            .contains(TextureUsages::TEXTURE_BINDING)
+        {
+            if missing_depth_binding.insert(entity) {
+                warn!(
+                    "OIT depth peeling needs the depth texture of {entity:?} to be bound, its \
+                     Camera3d::depth_texture_usages lost TextureUsages::TEXTURE_BINDING"
+                );
+            }
+            continue;
+        }
+        missing_depth_binding.remove(&entity);
+
+        let opaque_depth = textures
+            .opaque_depth
+            .as_ref()
+            .map_or(&depth.view, |texture| &texture.default_view);
+        let [depth_a, depth_b] = &textures.depth;
+        let peels = [
+            render_device.create_bind_group_ext(
+                "oit_depth_peeling_bind_group_even",
+                &layouts.depth_peeling,
+                [opaque_depth.bind(), depth_b.default_view.bind()],
+            ),
+            render_device.create_bind_group_ext(
+                "oit_depth_peeling_bind_group_odd",
+                &layouts.depth_peeling,
+                [opaque_depth.bind(), depth_a.default_view.bind()],
+            ),
+        ];
+        let copy_depth = textures.opaque_depth.is_some().then(|| {
+            render_device.create_bind_group_ext(
+                "oit_peel_copy_depth_bind_group",
+                &composite_pipeline.depth_layout,
+                [depth.view.bind()],
+            )
+        });
+        let color = render_device.create_bind_group_ext(
+            "oit_peel_color_bind_group",
+            &composite_pipeline.layout,
+            [textures.color.default_view.bind()],
+        );
+        let accumulation = render_device.create_bind_group_ext(
+            "oit_peel_accumulation_bind_group",
+            &composite_pipeline.layout,
+            [textures.accumulation.default_view.bind()],
+        );
+        commands.entity(entity).insert(OitDepthPeelingBindGroups {
+            peels,
+            color,
+            accumulation,
+            copy_depth,
+        });
+    }
+}
+
+/// Blends the peels together and on the view target
+#[derive(Resource)]
+pub struct OitDepthPeelingCompositePipeline {
+    layout: BindGroupLayout,
+    /// Reads the multisampled depth of the view
+    depth_layout: BindGroupLayout,
+}
+
+impl FromWorld for OitDepthPeelingCompositePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout_ext(
+            "oit_depth_peeling_composite_layout",
+            ShaderStages::FRAGMENT,
+            [texture_2d(
+                TextureSampleType::Float { filterable: false },
+                false,
+            )],
+        );
+        let depth_layout = render_device.create_bind_group_layout_ext(
+            "oit_depth_peeling_copy_depth_layout",
+            ShaderStages::FRAGMENT,
+            [texture_depth_2d(true)],
+        );
+        OitDepthPeelingCompositePipeline {
+            layout,
+            depth_layout,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OitDepthPeelingCompositeKey {
+    /// Blends a peel behind the previous ones in the accumulation texture
+    Peel,
+    /// Blends the accumulated peels on the view target
+    Resolve { msaa_samples: u32, hdr: bool },
+    /// Copies the first sample of the multisampled depth of the view
+    CopyDepth,
+}
+
+impl SpecializedRenderPipeline for OitDepthPeelingCompositePipeline {
+    type Key = OitDepthPeelingCompositeKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let builder = RenderPipelineDescriptorBuilder::fullscreen();
+
+        match key {
+            OitDepthPeelingCompositeKey::Peel => {
+                // UNDER operator using premultiplied alpha, the accumulated peels are in front
+                let under = BlendComponent {
+                    src_factor: BlendFactor::OneMinusDstAlpha,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                };
+                builder
+                    .label("oit_depth_peeling_peel_pipeline")
+                    .layout(vec![self.layout.clone()])
+                    .fragment(
+                        OIT_DEPTH_PEELING_COMPOSITE_SHADER_HANDLE.typed(),
+                        "fragment",
+                        &[ColorTargetState {
+                            format: PEEL_COLOR_TEXTURE_FORMAT,
+                            blend: Some(BlendState {
+                                color: under,
+                                alpha: under,
+                            }),
+                            write_mask: ColorWrites::ALL,
+                        }],
+                        &["OIT_PEEL".into()],
+                    )
+                    .build()
+            }
+            OitDepthPeelingCompositeKey::Resolve { msaa_samples, hdr } => builder
+                .label("oit_depth_peeling_resolve_pipeline")
+                .layout(vec![self.layout.clone()])
+                .fragment(
+                    OIT_DEPTH_PEELING_COMPOSITE_SHADER_HANDLE.typed(),
+                    "fragment",
+                    &[ColorTargetState {
+                        format: if hdr {
+                            ViewTarget::TEXTURE_FORMAT_HDR
+                        } else {
+                            TextureFormat::bevy_default()
+                        },
+                        blend: Some(BlendState {
+                            color: BlendComponent::OVER,
+                            alpha: BlendComponent::OVER,
+                        }),
+                        write_mask: ColorWrites::ALL,
+                    }],
+                    &[],
+                )
+                .multisample_state(MultisampleState {
+                    count: msaa_samples,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                })
+                .build(),
+            OitDepthPeelingCompositeKey::CopyDepth => builder
+                .label("oit_depth_peeling_copy_depth_pipeline")
+                .layout(vec![self.depth_layout.clone()])
+                .fragment(
+                    OIT_DEPTH_PEELING_COMPOSITE_SHADER_HANDLE.typed(),
+                    "copy_depth",
+                    &[],
+                    &["OIT_COPY_DEPTH".into()],
+                )
+                .depth_stencil(DepthStencilState {
+                    format: PEEL_DEPTH_TEXTURE_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: CompareFunction::Always,
+                    stencil: StencilState::default(),
+                    bias: DepthBiasState::default(),
+                })
+                .build(),
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct OitDepthPeelingPipelineIds {
+    pub peel: CachedRenderPipelineId,
+    pub resolve: CachedRenderPipelineId,
+    /// Only used with MSAA
+    pub copy_depth: Option<CachedRenderPipelineId>,
+}
+
+pub fn queue_depth_peeling_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    composite_pipeline: Res<OitDepthPeelingCompositePipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<OitDepthPeelingCompositePipeline>>,
+    views: Query<(Entity, &ExtractedView, &OitCamera)>,
+    msaa: Res<Msaa>,
+) {
+    for (entity, view, oit_camera) in &views {
+        let OitMethod::DepthPeeling { .. } = oit_camera.method else {
+            continue;
+        };
+
+        let peel = pipelines.specialize(
+            &pipeline_cache,
+            &composite_pipeline,
+            OitDepthPeelingCompositeKey::Peel,
+        );
+        let resolve = pipelines.specialize(
+            &pipeline_cache,
+            &composite_pipeline,
+            OitDepthPeelingCompositeKey::Resolve {
+                msaa_samples: msaa.samples(),
+                hdr: view.hdr,
+            },
+        );
+        let copy_depth = (msaa.samples() > 1).then(|| {
+            pipelines.specialize(
+                &pipeline_cache,
+                &composite_pipeline,
+                OitDepthPeelingCompositeKey::CopyDepth,
+            )
+        });
+        commands.entity(entity).insert(OitDepthPeelingPipelineIds {
+            peel,
+            resolve,
+            copy_depth,
+        });
+    }
+}
@@ -0,0 +1,31 @@
+#ifdef OIT_COPY_DEPTH
+@group(0) @binding(0)
+var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0)
+var color_texture: texture_2d<f32>;
+#endif
+
+struct FullscreenVertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+#ifdef OIT_COPY_DEPTH
+// Copies the first sample of the multisampled opaque depth for the peels, which aren't multisampled
+@fragment
+fn copy_depth(in: FullscreenVertexOutput) -> @builtin(frag_depth) f32 {
+    return textureLoad(depth_texture, vec2<i32>(floor(in.position.xy)), 0);
+}
+#else
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let color = textureLoad(color_texture, vec2<i32>(floor(in.position.xy)), 0);
+#ifdef OIT_PEEL
+    // The peels store the color returned by the material, it isn't premultiplied
+    return vec4(color.rgb * color.a, color.a);
+#else
+    return color;
+#endif
+}
+#endif
@@ -7,49 +7,71 @@
 
 use bevy::{
     asset::load_internal_asset,
-    core_pipeline::core_3d::{self, CORE_3D},
-    ecs::{
-        query::ROQueryItem,
-        system::{
-            lifetimeless::{Read, SRes},
-            SystemParamItem,
-        },
+    core_pipeline::{
+        core_2d::{self, CORE_2D},
+        core_3d::{self, Transparent3d, CORE_3D},
     },
-    pbr::{DrawMesh, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    ecs::{query::ROQueryItem, system::SystemParamItem},
+    pbr::CLUSTERED_FORWARD_STORAGE_BUFFER_COUNT,
     prelude::*,
     reflect::TypeUuid,
     render::{
         camera::ExtractedCamera,
         extract_component::{ExtractComponent, ExtractComponentPlugin, UniformComponentPlugin},
-        render_asset::RenderAssets,
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
         render_graph::{RenderGraphApp, ViewNodeRunner},
         render_phase::{
-            sort_phase_system, AddRenderCommand, CachedRenderPipelinePhaseItem, DrawFunctionId,
-            DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult, RenderPhase,
-            SetItemPipeline, TrackedRenderPass,
+            sort_phase_system, CachedRenderPipelinePhaseItem, DrawFunctionId, DrawFunctions,
+            PhaseItem, RenderCommand, RenderCommandResult, RenderPhase, TrackedRenderPass,
         },
         render_resource::{
-            BindGroup, CachedRenderPipelineId, PipelineCache, ShaderType, SpecializedMeshPipelines,
-            StorageBuffer,
+            BindGroup, CachedRenderPipelineId, ShaderType, SpecializedRenderPipelines,
+            TextureUsages,
         },
-        renderer::{RenderDevice, RenderQueue},
-        view::{ExtractedView, VisibleEntities},
+        renderer::{RenderAdapter, RenderDevice},
+        view::ExtractedView,
         Extract, Render, RenderApp, RenderSet,
     },
-    utils::FloatOrd,
+    utils::{FloatOrd, HashSet},
+};
+pub use depth_bias::OitDepthBias;
+use depth_bias::OitDrawUniformOffset;
+pub use depth_output::OitDepthOutputTexture;
+use depth_peeling::OitDepthPeelingPlugin;
+use pipeline::{OitBuffer, OitBuffers, OitLayersBindGroupLayout, OitRenderPipeline};
+use weighted_blended::OitCompositePipeline;
+use wgpu::DownlevelFlags;
+
+use crate::{
+    material::{OitMaterialPlugin, StandardOitMaterial},
+    material_2d::{ColorOitMaterial, OitMaterial2dPlugin},
+    node::OitNode,
+    standard_material::OitStandardMaterialPlugin,
 };
-use material::OitMaterial;
-use pipeline::{OitBuffers, OitKey, OitRenderPipeline};
 
-use crate::{material::OitMaterialPlugin, node::OitNode, pipeline::OitDrawPipeline};
+/// The number of layers used by an [`OitCamera`] when it isn't configured
+pub const DEFAULT_OIT_LAYERS: usize = 8;
 
-// TODO make this runtime configurable
-pub const OIT_LAYERS: usize = 8;
+/// The maximum number of layers of an [`OitCamera`], bigger values are clamped.
+///
+/// The resolve pass sorts the fragments of each pixel in a private array sized from the number
+/// of layers, so big values use a lot of registers.
+pub const MAX_OIT_LAYERS: usize = 64;
 
+mod depth_bias;
+mod depth_output;
+mod depth_peeling;
+mod linked_list;
 pub mod material;
+pub mod material_2d;
 mod node;
 mod pipeline;
+pub mod reference;
+mod shadow;
+mod standard_material;
+mod transparent_3d;
 mod utils;
+mod weighted_blended;
 
 #[allow(clippy::unreadable_literal)]
 pub const OIT_DRAW_SHADER_HANDLE: HandleUntyped =
@@ -59,54 +81,538 @@ pub const OIT_DRAW_SHADER_HANDLE: HandleUntyped =
 pub const OIT_DRAW_BINDINGS_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3431342664581120);
 
+#[allow(clippy::unreadable_literal)]
+pub const OIT_LAYER_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 8817370123362304);
+
+#[allow(clippy::unreadable_literal)]
+pub const STANDARD_OIT_MATERIAL_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 7270914285406208);
+
+#[allow(clippy::unreadable_literal)]
+pub const STANDARD_OIT_MATERIAL_SHADOW_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 4517630852818944);
+
+#[allow(clippy::unreadable_literal)]
+pub const COLOR_OIT_MATERIAL_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 5306927145811968);
+
+#[allow(clippy::unreadable_literal)]
+pub const OIT_PBR_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 2089123480137216);
+
 #[allow(clippy::unreadable_literal)]
 pub const OIT_RENDER_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 1612685519093760);
 
-#[derive(Component, Clone, Copy, ExtractComponent, Default)]
+#[allow(clippy::unreadable_literal)]
+pub const OIT_WEIGHTED_BLENDED_COMPOSITE_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 6344103925235712);
+
+#[allow(clippy::unreadable_literal)]
+pub const OIT_DEPTH_PEELING_COMPOSITE_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3981502274732032);
+
+/// The algorithm used to render the OIT phase of an [`OitCamera`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OitMethod {
+    /// Stores up to [`OitCamera::layers`] fragments for each pixel and sorts them in a resolve
+    /// pass.
+    ///
+    /// The result is exact as long as there are enough layers but the memory usage grows with
+    /// the size of the target and the number of layers.
+    ///
+    /// With MSAA the samples covered by each fragment are stored in the layers and the resolve
+    /// pass runs once per sample, so the edges of the transparent meshes are antialiased.
+    /// The coverage uses the least significant bits of the depth, one per sample.
+    #[default]
+    ABuffer,
+    /// Weighted blended OIT, see <https://jcgt.org/published/0002/02/09/>.
+    ///
+    /// Fragments are accumulated in an accumulation and a revealage render target using a depth
+    /// based weight and composited on the view target. It only needs 9 bytes per sample
+    /// whatever the depth complexity but the result is an approximation of the sorted blend.
+    WeightedBlended,
+    /// Multi-layer alpha blending, see
+    /// <https://software.intel.com/content/www/us/en/develop/articles/multi-layer-alpha-blending.html>.
+    ///
+    /// Uses the same storage as [`OitMethod::ABuffer`] but each fragment is merged in
+    /// [`OitCamera::layers`] blended layers kept sorted from front to back. When all the layers
+    /// are used the two farthest ones are merged so fragments are never dropped, only the order
+    /// of the farthest ones gets approximated.
+    ///
+    /// The layers of a pixel are locked while a fragment updates them, so this is slower than the
+    /// A-buffer when many fragments land on the same pixel. WGSL only has relaxed atomics so the
+    /// lock doesn't guarantee a fragment sees every write of the previous one, and the order the
+    /// fragments take the lock in changes the merged layers, so the result is best effort and can
+    /// change from frame to frame. Fragments that can't take the lock after a while are blended
+    /// directly without sorting. This is logged and shown by [`OitDebugView::Overflow`].
+    ///
+    /// Merged layers don't have a coverage so with MSAA only the fragments covering the last
+    /// sample of a pixel are kept and the edges aren't antialiased.
+    MultiLayerAlphaBlending,
+    /// Stores the fragments in per pixel linked lists allocated from a pool shared by the whole
+    /// target, and sorts them in a resolve pass.
+    ///
+    /// The memory usage grows with the number of transparent fragments instead of the size of
+    /// the target times the number of layers. Only the nearest [`OitCamera::layers`] fragments of
+    /// each pixel are blended. Fragments that don't fit in the pool are dropped or, with
+    /// [`OitCamera::tail_blend`], blended directly. The overflow is logged and shown by
+    /// [`OitDebugView::Overflow`].
+    ///
+    /// MSAA is supported the same way as [`OitMethod::ABuffer`].
+    LinkedList {
+        /// The number of fragments the pool can hold for the whole target.
+        ///
+        /// Each fragment uses 16 bytes. The pool holds at least one fragment, smaller values are
+        /// clamped.
+        nodes: usize,
+    },
+    /// Renders the OIT phase once per peel, each peel keeps the nearest fragment of each pixel
+    /// that is behind the previous peel. The peels are then blended from front to back.
+    ///
+    /// This doesn't need storage buffers in fragment shaders so it's used instead of the other
+    /// methods when the device doesn't support them, see [`OitSupport`]. The result is exact for
+    /// the first `peels` fragments of each pixel but every peel draws all the transparent meshes
+    /// again. Fragments with exactly the same depth are peeled together so only one of them is
+    /// visible.
+    ///
+    /// The fragments are tested against the depth texture of the camera in the shader so
+    /// [`TextureUsages::TEXTURE_BINDING`] is added to its [`Camera3d::depth_texture_usages`].
+    /// The peels aren't multisampled, with MSAA the first sample of the depth is copied before
+    /// the first peel. 2d cameras don't have a depth texture so they use
+    /// [`OitMethod::WeightedBlended`] instead.
+    DepthPeeling {
+        /// The number of peels, the fallback uses [`OitCamera::layers`].
+        peels: usize,
+    },
+    /// Draws the meshes in bevy's [`Transparent3d`] or [`Transparent2d`] phase, sorted by the
+    /// distance of their origin like the [`AlphaMode::Blend`] meshes. The fragments of a mesh
+    /// and of intersecting meshes aren't sorted.
+    ///
+    /// This is what the cameras fall back to when the device can't render any of the other
+    /// methods, see [`OitSupport`]. The [`StandardMaterial`] meshes are left in bevy's phase
+    /// whatever [`OitCamera::standard_materials`] is.
+    ///
+    /// [`Transparent2d`]: bevy::core_pipeline::core_2d::Transparent2d
+    TransparentPhase,
+}
+
+impl OitMethod {
+    /// Whether the method stores the fragments in the OIT layers buffers and resolves
+    /// them in a separate pass.
+    pub(crate) fn uses_layers(self) -> bool {
+        matches!(
+            self,
+            Self::ABuffer | Self::MultiLayerAlphaBlending | Self::LinkedList { .. }
+        )
+    }
+
+    /// Whether the layers store the samples covered by each fragment so the resolve pass runs
+    /// per sample.
+    pub(crate) fn stores_coverage(self, k_buffer: bool, msaa_samples: u32) -> bool {
+        msaa_samples > 1
+            && match self {
+                Self::ABuffer => !k_buffer,
+                Self::LinkedList { .. } => true,
+                _ => false,
+            }
+    }
+}
+
+/// Replaces the resolved colors of an [`OitCamera`] with a heat map.
+///
+/// The heat map goes from blue to cyan, green, yellow and red. This is only supported by
+/// [`OitMethod::ABuffer`], [`OitMethod::MultiLayerAlphaBlending`] and
+/// [`OitMethod::LinkedList`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OitDebugView {
+    #[default]
+    None,
+    /// The number of layers used by each pixel, red means all the layers are used.
+    LayerCount,
+    /// The number of fragments that didn't fit in the layers and were tail blended or dropped.
+    /// Pixels that fit in the layers are dark green and red means there were twice as many
+    /// fragments as layers.
+    ///
+    /// With [`OitMethod::LinkedList`] the pixels that lost fragments because the pool was full
+    /// are magenta. With [`OitMethod::MultiLayerAlphaBlending`] it's the pixels that had
+    /// fragments blended without sorting because they couldn't take the lock of the layers.
+    Overflow,
+    /// The number of transparent fragments drawn on each pixel, red means twice the number of
+    /// layers.
+    DepthComplexity,
+}
+
+/// The depth of the transparent fragments written by an [`OitCamera`] in its
+/// [`OitDepthOutputTexture`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OitDepthOutput {
+    /// The depth of the nearest transparent fragment.
+    Nearest,
+    /// The depth of the fragment where the alpha of the fragments blended from front to back
+    /// reaches this value, between 0.0 and 1.0. Pixels that stay more transparent than this
+    /// don't get a depth.
+    ///
+    /// The threshold is rounded to a multiple of 1/255.
+    AlphaThreshold(f32),
+}
+
+impl OitDepthOutput {
+    /// The alpha threshold in 1/255 steps, so it can be used to specialize a pipeline
+    #[allow(clippy::cast_sign_loss)]
+    pub(crate) fn alpha_threshold(self) -> u32 {
+        match self {
+            Self::Nearest => 0,
+            Self::AlphaThreshold(threshold) => (threshold.clamp(0.0, 1.0) * 255.0).round() as u32,
+        }
+    }
+}
+
+/// Renders the [`OitMaterial`](material::OitMaterial) meshes of a 3d camera, or the
+/// [`OitMaterial2d`](material_2d::OitMaterial2d) meshes of a 2d camera, with order independent
+/// transparency.
+///
+/// 2d cameras don't have a depth texture, the transparent fragments are sorted by their depth in
+/// the orthographic projection, so by the `z` of the meshes, and resolved on top of bevy's
+/// [`Transparent2d`](core_2d::Transparent2d) phase.
+///
+/// With a [`MotionVectorPrepass`](bevy::core_pipeline::prepass::MotionVectorPrepass), the methods
+/// storing the fragments in layers also write the camera motion of the most visible transparent
+/// fragment of each pixel in the motion vectors, so the temporal anti-aliasing follows the
+/// transparent surfaces instead of the ones behind them.
+#[derive(Component, Clone, Copy, ExtractComponent)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct OitCamera {
-    // TODO docs
+    /// The algorithm used to render the transparent meshes.
+    pub method: OitMethod,
+    /// The maximum number of transparent fragments stored for each pixel.
+    ///
+    /// Each layer requires a `vec2<u32>` per pixel of the camera target so the memory usage
+    /// grows linearly with this value. When the camera is `hdr` the colors are stored as rgb9e5
+    /// so they don't get clamped to 1.0.
+    ///
+    /// This is clamped between 1 and [`MAX_OIT_LAYERS`].
+    pub layers: usize,
+    /// Blend the fragments that don't fit in the layers directly on the target instead of
+    /// dropping them.
+    ///
+    /// Dropped fragments leave holes where many transparent surfaces overlap. Tail blended ones
+    /// keep their color but they are blended in the order they are drawn, behind the sorted
+    /// layers. With [`OitCamera::k_buffer`] the farthest fragments are the ones tail blended and
+    /// with [`OitMethod::LinkedList`] it's the fragments that didn't fit in the pool.
+    /// [`OitMethod::MultiLayerAlphaBlending`] merges them in the last layer so this isn't used.
     pub tail_blend: bool,
+    /// Keep the nearest [`OitCamera::layers`] fragments of each pixel instead of the first ones
+    /// that were drawn. Only used by [`OitMethod::ABuffer`].
+    ///
+    /// This makes the result independent of the order the meshes are drawn in when there are
+    /// more fragments than layers, and the farthest ones are the ones that get tail blended or
+    /// dropped. The layers are inserted in order with atomics, so each one is packed in a single
+    /// `u32`. The depth is a `f16` and each color channel only gets 4 bits, so smooth gradients
+    /// get visible banding and the alpha is rounded to steps of 1/15.
+    ///
+    /// There is no room for colors above 1.0 or the coverage of MSAA, so this is ignored with a
+    /// warning by `hdr` cameras and when MSAA is enabled.
+    pub k_buffer: bool,
+    /// Render [`StandardMaterial`] meshes using [`AlphaMode::Blend`] with OIT instead of the
+    /// sorted transparent phase.
+    pub standard_materials: bool,
+    /// Blend the items of bevy's [`Transparent3d`] phase, like the [`AlphaMode::Blend`] meshes
+    /// or the gizmos, with the OIT fragments at their depth instead of behind all of them.
+    ///
+    /// The resolve pass is split at the depth of each item, so every distinct item depth adds a
+    /// resolve pass. The items are still sorted by the depth of their origin like in bevy's
+    /// phase. Only used by 3d cameras with the methods storing the fragments in layers and
+    /// ignored with a [`OitCamera::debug_view`].
+    pub merge_transparent_3d: bool,
+    /// Write the depth of the transparent fragments in an [`OitDepthOutputTexture`] so the
+    /// passes running after the OIT node, like depth of field or fog, can treat them as
+    /// surfaces.
+    ///
+    /// This adds a fullscreen pass reading the layers so it's only supported by the methods
+    /// storing the fragments in layers.
+    pub depth_output: Option<OitDepthOutput>,
+    /// Shows how the layers are used instead of the transparent meshes.
+    pub debug_view: OitDebugView,
+}
+
+impl Default for OitCamera {
+    fn default() -> Self {
+        Self {
+            method: OitMethod::default(),
+            layers: DEFAULT_OIT_LAYERS,
+            tail_blend: false,
+            k_buffer: false,
+            standard_materials: false,
+            merge_transparent_3d: false,
+            depth_output: None,
+            debug_view: OitDebugView::None,
+        }
+    }
+}
+
+/// What the render device supports, extracted from its limits and downlevel capabilities when
+/// the plugin finishes. It's available in the main world and the render world.
+///
+/// The [`OitCamera`]s using a method the device can't render, see [`OitSupport::method`], use
+/// [`OitMethod::DepthPeeling`] instead, or [`OitMethod::WeightedBlended`] for 2d cameras. When
+/// that isn't supported either, like on WebGL2 with MSAA, they use
+/// [`OitMethod::TransparentPhase`].
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct OitSupport {
+    /// Whether fragment shaders can use the read write storage buffers and atomics that store
+    /// the OIT layers.
+    pub storage_buffers: bool,
+    /// Whether the color targets of a pipeline can use different blend states, which
+    /// [`OitMethod::WeightedBlended`] needs for its accumulation and revealage targets.
+    pub independent_blend: bool,
+    /// Whether shaders can read multisampled textures, which [`OitMethod::WeightedBlended`] and
+    /// [`OitMethod::DepthPeeling`] need with MSAA.
+    pub multisampled_textures: bool,
+}
+
+/// The number of storage buffers bound by the OIT draw pass
+const OIT_STORAGE_BUFFER_COUNT: u32 = 2;
+
+impl FromWorld for OitSupport {
+    fn from_world(world: &mut World) -> Self {
+        let max_storage_buffers = world
+            .resource::<RenderDevice>()
+            .limits()
+            .max_storage_buffers_per_shader_stage;
+        // The mesh view bindings also use storage buffers for the clusters when they can
+        let clusters = if max_storage_buffers >= CLUSTERED_FORWARD_STORAGE_BUFFER_COUNT {
+            CLUSTERED_FORWARD_STORAGE_BUFFER_COUNT
+        } else {
+            0
+        };
+        // WebGL2 reports storage buffers in its limits but can't write them in fragment shaders
+        let downlevel = world
+            .resource::<RenderAdapter>()
+            .get_downlevel_capabilities();
+        OitSupport {
+            storage_buffers: max_storage_buffers >= clusters + OIT_STORAGE_BUFFER_COUNT
+                && downlevel
+                    .flags
+                    .contains(DownlevelFlags::FRAGMENT_WRITABLE_STORAGE),
+            independent_blend: downlevel.flags.contains(DownlevelFlags::INDEPENDENT_BLEND),
+            // There is no flag for it, shaders can read multisampled textures since GLES 3.1
+            // which also added the compute shaders. WebGL2 is based on GLES 3.0.
+            multisampled_textures: downlevel.flags.contains(DownlevelFlags::COMPUTE_SHADERS),
+        }
+    }
+}
+
+impl OitSupport {
+    /// The method an [`OitCamera`] using `method` is rendered with on this device.
+    ///
+    /// `layers` is used for the peels when falling back to [`OitMethod::DepthPeeling`].
+    #[must_use]
+    pub fn method(
+        self,
+        method: OitMethod,
+        layers: usize,
+        is_2d: bool,
+        msaa_samples: u32,
+    ) -> OitMethod {
+        let multisampled = msaa_samples == 1 || self.multisampled_textures;
+        let supported = |method: OitMethod| match method {
+            OitMethod::WeightedBlended => self.independent_blend && multisampled,
+            // 2d cameras don't have a depth texture
+            OitMethod::DepthPeeling { .. } => !is_2d && multisampled,
+            OitMethod::TransparentPhase => true,
+            // The methods storing the fragments in layers
+            _ => self.storage_buffers,
+        };
+        let fallback = if is_2d {
+            OitMethod::WeightedBlended
+        } else {
+            OitMethod::DepthPeeling { peels: layers }
+        };
+        [method, fallback]
+            .into_iter()
+            .find(|method| supported(*method))
+            .unwrap_or(OitMethod::TransparentPhase)
+    }
+}
+
+/// Controls how the A-buffer storage of the [`OitCamera`]s is allocated.
+///
+/// This is extracted to the render world every frame so it can be changed at runtime.
+#[derive(Resource, Clone, Copy, Debug, ExtractResource)]
+pub struct OitBufferSettings {
+    /// The buffers of a camera are shrunk when its target needs less than this fraction of the
+    /// allocated pixels, for example after a window got smaller.
+    ///
+    /// When `None` the buffers keep the size of the biggest target they were used with.
+    pub shrink_threshold: Option<f32>,
+    /// The maximum number of bytes that can be used by the buffers of all the cameras.
+    ///
+    /// Cameras that would go over the budget don't get any buffers and skip the OIT pass.
+    pub memory_budget: Option<u64>,
+    /// How the number of pixels allocated is rounded up when the buffers get resized.
+    pub growth: OitBufferGrowth,
+    /// The number of frames the size of a target needs to stay the same before its buffers get
+    /// resized. This avoids allocating new buffers every frame while a window is being resized.
+    pub resize_debounce_frames: u32,
+}
+
+impl Default for OitBufferSettings {
+    fn default() -> Self {
+        Self {
+            shrink_threshold: Some(0.5),
+            memory_budget: None,
+            growth: OitBufferGrowth::Exact,
+            resize_debounce_frames: 0,
+        }
+    }
+}
+
+/// How the number of pixels allocated for the OIT buffers grows with the size of the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OitBufferGrowth {
+    /// Allocate exactly the number of pixels of the target.
+    Exact,
+    /// Round the number of pixels up to the next power of two.
+    ///
+    /// This can almost double the memory used, a 1920x1080 target allocates 4194304 pixels.
+    PowerOfTwo,
+    /// Round the number of pixels up to a multiple of the given number of pixels.
+    Chunk(usize),
+}
+
+impl OitBufferGrowth {
+    /// Returns the number of pixels to allocate for a target with `size` pixels.
+    #[must_use]
+    pub fn capacity(self, size: usize) -> usize {
+        match self {
+            OitBufferGrowth::Exact => size,
+            OitBufferGrowth::PowerOfTwo => size.next_power_of_two(),
+            OitBufferGrowth::Chunk(chunk) => {
+                let chunk = chunk.max(1);
+                size.div_ceil(chunk) * chunk
+            }
+        }
+    }
+}
+
+fn load_shaders(app: &mut App) {
+    load_internal_asset!(
+        app,
+        OIT_DRAW_SHADER_HANDLE,
+        "oit_draw.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        OIT_DRAW_BINDINGS_SHADER_HANDLE,
+        "oit_draw_bindings.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        OIT_LAYER_SHADER_HANDLE,
+        "oit_layer.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        STANDARD_OIT_MATERIAL_SHADER_HANDLE,
+        "standard_oit_material.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        STANDARD_OIT_MATERIAL_SHADOW_SHADER_HANDLE,
+        "standard_oit_material_shadow.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        COLOR_OIT_MATERIAL_SHADER_HANDLE,
+        "color_oit_material.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        OIT_PBR_SHADER_HANDLE,
+        "oit_pbr.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        OIT_RENDER_SHADER_HANDLE,
+        "oit_render.wgsl",
+        Shader::from_wgsl
+    );
+    load_internal_asset!(
+        app,
+        OIT_WEIGHTED_BLENDED_COMPOSITE_SHADER_HANDLE,
+        "weighted_blended_composite.wgsl",
+        Shader::from_wgsl
+    );
 }
 
 pub struct OitPlugin;
 impl Plugin for OitPlugin {
     fn build(&self, app: &mut App) {
-        load_internal_asset!(
-            app,
-            OIT_DRAW_SHADER_HANDLE,
-            "oit_draw.wgsl",
-            Shader::from_wgsl
-        );
-        load_internal_asset!(
-            app,
-            OIT_DRAW_BINDINGS_SHADER_HANDLE,
-            "oit_draw_bindings.wgsl",
-            Shader::from_wgsl
-        );
-        load_internal_asset!(
-            app,
-            OIT_RENDER_SHADER_HANDLE,
-            "oit_render.wgsl",
-            Shader::from_wgsl
-        );
-
-        app.add_plugins((
+        load_shaders(app);
+
+        app.init_resource::<OitBufferSettings>().add_plugins((
             UniformComponentPlugin::<OitMaterialUniform>::default(),
             ExtractComponentPlugin::<OitCamera>::default(),
-            OitMaterialPlugin,
+            ExtractComponentPlugin::<OitDepthBias>::default(),
+            ExtractResourcePlugin::<OitBufferSettings>::default(),
+            OitMaterialPlugin::<StandardOitMaterial>::default(),
+            OitMaterial2dPlugin::<ColorOitMaterial>::default(),
+            OitStandardMaterialPlugin,
+            OitDepthPeelingPlugin,
         ));
+        // The depth texture usages are read when the cameras are extracted
+        app.add_systems(PostUpdate, add_depth_peeling_texture_usage);
 
         let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
             return;
         };
 
         render_app
-            .init_resource::<SpecializedMeshPipelines<OitDrawPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<OitRenderPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<OitCompositePipeline>>()
             .init_resource::<DrawFunctions<OitPhaseItem>>()
+            .init_resource::<DrawFunctions<OitPhaseItem2d>>()
             .init_resource::<OitBuffers>()
-            .add_render_command::<OitPhaseItem, DrawOit>()
-            .add_systems(Render, prepare_buffers.in_set(RenderSet::Prepare));
+            .init_resource::<linked_list::OitNodeCounterReadbacks>()
+            .init_resource::<transparent_3d::OitDepthSliceUniforms>()
+            .init_resource::<depth_bias::OitDrawUniforms>()
+            .add_systems(
+                Render,
+                (
+                    clamp_oit_layers
+                        .in_set(RenderSet::Prepare)
+                        .before(fallback_oit_method),
+                    disable_unsupported_k_buffer
+                        .in_set(RenderSet::Prepare)
+                        .before(fallback_oit_method),
+                    fallback_oit_method
+                        .in_set(RenderSet::Prepare)
+                        .before(cleanup_buffers)
+                        .before(depth_peeling::prepare_depth_peeling_textures)
+                        .before(depth_output::prepare_depth_output_textures),
+                    cleanup_buffers
+                        .in_set(RenderSet::Prepare)
+                        .before(prepare_buffers),
+                    prepare_buffers.in_set(RenderSet::Prepare),
+                    linked_list::prepare_node_pools
+                        .in_set(RenderSet::Prepare)
+                        .after(prepare_buffers),
+                    weighted_blended::prepare_weighted_blended_textures
+                        .in_set(RenderSet::Prepare)
+                        .after(fallback_oit_method),
+                    depth_output::prepare_depth_output_textures.in_set(RenderSet::Prepare),
+                    depth_bias::prepare_draw_uniforms.in_set(RenderSet::Prepare),
+                ),
+            );
 
         render_app
             .add_systems(ExtractSchedule, extract_render_phase)
@@ -114,17 +620,45 @@ impl Plugin for OitPlugin {
                 Render,
                 (
                     sort_phase_system::<OitPhaseItem>.in_set(RenderSet::PhaseSort),
-                    queue_mesh_oit_phase.in_set(RenderSet::Queue),
+                    sort_phase_system::<OitPhaseItem2d>.in_set(RenderSet::PhaseSort),
+                    transparent_3d::prepare_resolve_slices
+                        .in_set(RenderSet::PhaseSort)
+                        .after(sort_phase_system::<OitPhaseItem>)
+                        .after(sort_phase_system::<Transparent3d>),
                     pipeline::queue_bind_groups.in_set(RenderSet::Queue),
                     pipeline::queue_render_oit_pipeline.in_set(RenderSet::Queue),
+                    weighted_blended::queue_weighted_blended_bind_groups.in_set(RenderSet::Queue),
+                    weighted_blended::queue_composite_pipeline.in_set(RenderSet::Queue),
+                    linked_list::map_node_counter_readbacks.in_set(RenderSet::Cleanup),
                 ),
             );
 
         render_app
-            .add_render_graph_node::<ViewNodeRunner<OitNode>>(CORE_3D, OitNode::NAME)
+            .add_render_graph_node::<ViewNodeRunner<OitNode<OitPhaseItem>>>(
+                CORE_3D,
+                OitNode::<OitPhaseItem>::NAME,
+            )
             .add_render_graph_edges(
                 CORE_3D,
-                &[core_3d::graph::node::MAIN_TRANSPARENT_PASS, OitNode::NAME],
+                &[
+                    core_3d::graph::node::MAIN_TRANSPARENT_PASS,
+                    OitNode::<OitPhaseItem>::NAME,
+                    // The temporal anti-aliasing runs after the main passes and reads the motion
+                    // vectors written by the node
+                    core_3d::graph::node::END_MAIN_PASS,
+                ],
+            )
+            .add_render_graph_node::<ViewNodeRunner<OitNode<OitPhaseItem2d>>>(
+                CORE_2D,
+                OitNode::<OitPhaseItem2d>::NAME,
+            )
+            .add_render_graph_edges(
+                CORE_2D,
+                &[
+                    core_2d::graph::node::MAIN_PASS,
+                    OitNode::<OitPhaseItem2d>::NAME,
+                    core_2d::graph::node::TONEMAPPING,
+                ],
             );
     }
 
@@ -133,8 +667,16 @@ impl Plugin for OitPlugin {
             return;
         };
         render_app
-            .init_resource::<OitDrawPipeline>()
-            .init_resource::<OitRenderPipeline>();
+            .init_resource::<OitSupport>()
+            .init_resource::<OitLayersBindGroupLayout>()
+            .init_resource::<OitCompositePipeline>();
+
+        // The resolve pass reads the layers from storage buffers
+        let support = *render_app.world.resource::<OitSupport>();
+        if support.storage_buffers {
+            render_app.init_resource::<OitRenderPipeline>();
+        }
+        app.insert_resource(support);
     }
 }
 
@@ -167,59 +709,66 @@ impl CachedRenderPipelinePhaseItem for OitPhaseItem {
     }
 }
 
-struct SetOitMaterialBindGroup<const I: usize>;
-impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetOitMaterialBindGroup<I> {
-    type Param = SRes<RenderAssets<OitMaterial>>;
-    type ViewWorldQuery = ();
-    type ItemWorldQuery = Read<Handle<OitMaterial>>;
+/// The phase item of the [`OitMaterial2d`](material_2d::OitMaterial2d) meshes drawn by 2d
+/// cameras.
+pub struct OitPhaseItem2d {
+    /// The `z` of the mesh, like bevy's [`Transparent2d`](core_2d::Transparent2d).
+    pub sort_key: FloatOrd,
+    pub pipeline: CachedRenderPipelineId,
+    pub entity: Entity,
+    pub draw_function: DrawFunctionId,
+}
 
-    #[inline]
-    fn render<'w>(
-        _item: &P,
-        _view: (),
-        material_handle: ROQueryItem<'w, Self::ItemWorldQuery>,
-        materials: SystemParamItem<'w, '_, Self::Param>,
-        pass: &mut TrackedRenderPass<'w>,
-    ) -> RenderCommandResult {
-        let Some(material) = materials.into_inner().get(material_handle) else {
-            return RenderCommandResult::Failure;
-        };
-        pass.set_bind_group(I, &material.bind_group, &[]);
-        RenderCommandResult::Success
+impl PhaseItem for OitPhaseItem2d {
+    type SortKey = FloatOrd;
+
+    fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    fn sort_key(&self) -> Self::SortKey {
+        self.sort_key
+    }
+
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for OitPhaseItem2d {
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
     }
 }
 
 #[derive(Component, Deref)]
 pub struct OitLayersBindGroup(pub BindGroup);
 
-struct SetOitLayersBindGroup<const I: usize>;
+pub(crate) struct SetOitLayersBindGroup<const I: usize>;
 impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetOitLayersBindGroup<I> {
     type Param = ();
-    type ViewWorldQuery = &'static OitLayersBindGroup;
-    type ItemWorldQuery = ();
+    type ViewWorldQuery = Option<&'static OitLayersBindGroup>;
+    type ItemWorldQuery = Option<&'static OitDrawUniformOffset>;
 
     #[inline]
     fn render<'w>(
         _item: &P,
         bind_group: ROQueryItem<'w, Self::ViewWorldQuery>,
-        _mesh_index: ROQueryItem<'w, Self::ItemWorldQuery>,
+        draw_uniform_offset: ROQueryItem<'w, Self::ItemWorldQuery>,
         _param: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        pass.set_bind_group(I, bind_group, &[]);
+        // Only the methods using the layers have this bind group, depth peeling binds its
+        // textures in the node
+        if let Some(bind_group) = bind_group {
+            // Entities without an OitDepthBias use the first uniform
+            let offset = draw_uniform_offset.map_or(0, |offset| offset.0);
+            pass.set_bind_group(I, bind_group, &[offset]);
+        }
         RenderCommandResult::Success
     }
 }
 
-type DrawOit = (
-    SetItemPipeline,
-    SetMeshViewBindGroup<0>,
-    SetOitMaterialBindGroup<1>,
-    SetMeshBindGroup<2>,
-    SetOitLayersBindGroup<3>,
-    DrawMesh,
-);
-
 #[derive(Component, ShaderType, Clone, Copy)]
 pub struct OitMaterialUniform {
     base_color: Color,
@@ -228,6 +777,7 @@ pub struct OitMaterialUniform {
 fn extract_render_phase(
     mut commands: Commands,
     cameras_3d: Extract<Query<(Entity, &Camera), With<Camera3d>>>,
+    cameras_2d: Extract<Query<(Entity, &Camera), With<Camera2d>>>,
 ) {
     for (entity, camera) in &cameras_3d {
         if camera.is_active {
@@ -236,113 +786,244 @@ fn extract_render_phase(
                 .insert(RenderPhase::<OitPhaseItem>::default());
         }
     }
+    for (entity, camera) in &cameras_2d {
+        if camera.is_active {
+            commands
+                .get_or_spawn(entity)
+                .insert(RenderPhase::<OitPhaseItem2d>::default());
+        }
+    }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn queue_mesh_oit_phase(
-    draw_functions: Res<DrawFunctions<OitPhaseItem>>,
-    pipeline: Res<OitDrawPipeline>,
-    mut pipelines: ResMut<SpecializedMeshPipelines<OitDrawPipeline>>,
-    pipeline_cache: Res<PipelineCache>,
-    render_meshes: Res<RenderAssets<Mesh>>,
-    meshes: Query<(Entity, &Handle<Mesh>, &MeshUniform), With<Handle<OitMaterial>>>,
-    mut views: Query<(
-        &ExtractedView,
-        &OitCamera,
-        &mut VisibleEntities,
-        &mut RenderPhase<OitPhaseItem>,
-    )>,
-    msaa: Res<Msaa>,
+/// Keeps [`OitCamera::layers`] in the range supported by the buffers and the resolve pass, and
+/// the node pool of [`OitMethod::LinkedList`] big enough to be bound.
+fn clamp_oit_layers(
+    mut cameras: Query<&mut OitCamera>,
+    mut warned: Local<bool>,
+    mut warned_nodes: Local<bool>,
 ) {
-    let draw_function = draw_functions.read().id::<DrawOit>();
+    for mut oit_camera in &mut cameras {
+        if oit_camera.method == (OitMethod::LinkedList { nodes: 0 }) {
+            if !*warned_nodes {
+                warn!("OitMethod::LinkedList needs at least 1 node, 1 node is used instead");
+                *warned_nodes = true;
+            }
+            oit_camera.method = OitMethod::LinkedList { nodes: 1 };
+        }
 
-    for (view, oit_camera, visible_entities, mut oit_phase) in &mut views {
-        let view_matrix = view.transform.compute_matrix();
-        let inv_view_row_2 = view_matrix.inverse().row(2);
+        let layers = oit_camera.layers.clamp(1, MAX_OIT_LAYERS);
+        if layers == oit_camera.layers {
+            continue;
+        }
+        if !*warned {
+            warn!(
+                "OitCamera::layers is {} but it needs to be between 1 and {MAX_OIT_LAYERS}, \
+                 {layers} layers are used instead",
+                oit_camera.layers
+            );
+            *warned = true;
+        }
+        oit_camera.layers = layers;
+    }
+}
 
-        let view_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+/// The k-buffer layers can't store the colors of `hdr` cameras or the coverage of MSAA, those
+/// cameras use the regular A-buffer instead.
+fn disable_unsupported_k_buffer(
+    msaa: Res<Msaa>,
+    mut cameras: Query<(&mut OitCamera, &ExtractedView)>,
+    mut warned: Local<bool>,
+) {
+    for (mut oit_camera, view) in &mut cameras {
+        if !oit_camera.k_buffer || (!view.hdr && msaa.samples() == 1) {
+            continue;
+        }
+        if !*warned {
+            warn!(
+                "OitCamera::k_buffer only stores 4 bits per color channel and no MSAA coverage, \
+                 it's ignored by hdr cameras and when MSAA is enabled"
+            );
+            *warned = true;
+        }
+        oit_camera.k_buffer = false;
+    }
+}
 
-        for visible_entity in visible_entities.entities.iter().copied() {
-            let Ok((entity, mesh_handle, mesh_uniform)) = meshes.get(visible_entity) else {
-                continue;
-            };
-            let Some(mesh) = render_meshes.get(mesh_handle) else {
-                continue;
-            };
-
-            let mesh_key =
-                MeshPipelineKey::from_primitive_topology(mesh.primitive_topology) | view_key;
-            let oit_key = OitKey {
-                mesh_key,
-                tail_blend: oit_camera.tail_blend,
-            };
-            let Ok(pipeline) =
-                pipelines.specialize(&pipeline_cache, &pipeline, oit_key, &mesh.layout)
-            else {
-                continue;
-            };
-
-            oit_phase.add(OitPhaseItem {
-                entity,
-                pipeline,
-                draw_function,
-                distance: inv_view_row_2.dot(mesh_uniform.transform.col(3)),
-            });
+/// Replaces the methods that need storage buffers when the device doesn't support them, and
+/// depth peeling for the 2d cameras since they don't have a depth texture
+fn fallback_oit_method(
+    support: Res<OitSupport>,
+    msaa: Res<Msaa>,
+    mut cameras: Query<(Entity, &mut OitCamera, Option<&RenderPhase<OitPhaseItem2d>>)>,
+    mut warned: Local<bool>,
+) {
+    for (entity, mut oit_camera, phase_2d) in &mut cameras {
+        let is_2d = phase_2d.is_some();
+        let method = support.method(oit_camera.method, oit_camera.layers, is_2d, msaa.samples());
+        if method == oit_camera.method {
+            continue;
         }
+        // The 2d cameras always use weighted blended OIT instead of depth peeling
+        let documented = is_2d
+            && method == OitMethod::WeightedBlended
+            && matches!(oit_camera.method, OitMethod::DepthPeeling { .. });
+        if !documented && !*warned {
+            warn!(
+                "The render device doesn't support {:?} with {} MSAA samples, {entity:?} falls \
+                 back to {method:?}, see OitSupport",
+                oit_camera.method,
+                msaa.samples()
+            );
+            *warned = true;
+        }
+        oit_camera.method = method;
     }
 }
 
+/// Adds [`TextureUsages::TEXTURE_BINDING`] to the depth texture of the 3d cameras rendered with
+/// [`OitMethod::DepthPeeling`], the peels test the fragments against it in the shader.
+///
+/// This runs in the main world so it's already set when the camera is extracted.
+fn add_depth_peeling_texture_usage(
+    support: Option<Res<OitSupport>>,
+    msaa: Res<Msaa>,
+    mut cameras: Query<(&OitCamera, &mut Camera3d)>,
+) {
+    let Some(support) = support else {
+        return;
+    };
+    for (oit_camera, mut camera_3d) in &mut cameras {
+        let method = support.method(oit_camera.method, oit_camera.layers, false, msaa.samples());
+        if !matches!(method, OitMethod::DepthPeeling { .. }) {
+            continue;
+        }
+        let usages = TextureUsages::from(camera_3d.depth_texture_usages);
+        // Only touches the camera when needed so it doesn't get changed every frame
+        if !usages.contains(TextureUsages::TEXTURE_BINDING) {
+            camera_3d.depth_texture_usages = (usages | TextureUsages::TEXTURE_BINDING).into();
+        }
+    }
+}
+
+/// Releases the buffers of cameras that were despawned, deactivated or don't use the A-buffer
+/// anymore.
+///
+/// Inactive cameras are not extracted so they don't have an [`ExtractedCamera`].
+fn cleanup_buffers(
+    cameras: Query<&OitCamera, With<ExtractedCamera>>,
+    mut buffers: ResMut<OitBuffers>,
+) {
+    buffers.retain(|entity, _| {
+        cameras
+            .get(*entity)
+            .is_ok_and(|oit_camera| oit_camera.method.uses_layers())
+    });
+}
+
 /// This creates the required buffers for each camera
+///
+/// Resizing only allocates new buffers on the GPU. The previous buffers keep being used until the
+/// new ones are created, pixels that don't fit in them are blended without sorting.
 #[allow(clippy::type_complexity)]
+#[allow(clippy::cast_precision_loss)]
 fn prepare_buffers(
     render_device: Res<RenderDevice>,
-    render_queue: Res<RenderQueue>,
-    cameras: Query<(Entity, &ExtractedCamera), (Changed<ExtractedCamera>, With<OitCamera>)>,
+    cameras: Query<
+        (Entity, &ExtractedCamera, &OitCamera),
+        Or<(Changed<ExtractedCamera>, Changed<OitCamera>)>,
+    >,
+    settings: Res<OitBufferSettings>,
     mut buffers: ResMut<OitBuffers>,
+    mut over_budget: Local<HashSet<Entity>>,
+    mut over_limits: Local<HashSet<Entity>>,
 ) {
-    for (entity, camera) in &cameras {
+    for (entity, camera, oit_camera) in &cameras {
+        if !oit_camera.method.uses_layers() {
+            continue;
+        }
+
         let Some(size) = camera.physical_target_size else {
             continue;
         };
 
         let size = (size.x * size.y) as usize;
+        // The linked lists don't store layers for each pixel, only the head of the list
+        let (layers, nodes) = match oit_camera.method {
+            OitMethod::LinkedList { nodes } => (0, nodes),
+            _ => (oit_camera.layers, 0),
+        };
+        if size == 0 {
+            continue;
+        }
 
-        if let Some((current_size, oit_layers_buffer, oit_layer_ids_buffer)) =
-            buffers.get_mut(&entity)
-        {
-            // resize buffers
-            if *current_size >= size {
-                // Don't resize if the buffer is already bigger
-                // This is technically wasting memory but it's a bit faster so...
-                return;
-            }
-
-            println!("curr: {current_size} new: {size}");
-
-            // TODO this is super slow, figure out a more efficient way to resize
-            // Consider debouncing
-            // Maybe hide the OIT pass while resizing or keep it centered somehow?
-
-            oit_layers_buffer
-                .get_mut()
-                .resize(size * OIT_LAYERS, UVec2::ZERO);
-            oit_layers_buffer.write_buffer(&render_device, &render_queue);
-
-            oit_layer_ids_buffer.get_mut().resize(size, 0);
-            oit_layer_ids_buffer.write_buffer(&render_device, &render_queue);
+        let size = match buffers.get_mut(&entity) {
+            Some(buffer) => {
+                let shrink = settings
+                    .shrink_threshold
+                    .is_some_and(|threshold| (size as f32) < buffer.size as f32 * threshold)
+                    && settings.growth.capacity(size) < buffer.size;
+                let same_layout = buffer.layers == layers && buffer.nodes == nodes;
+                if buffer.size >= size && same_layout && !shrink {
+                    // Don't resize if the buffer is already bigger
+                    // This is technically wasting memory but it's a bit faster so...
+                    buffer.pending_frames = 0;
+                    continue;
+                }
 
-            *current_size = size;
-        } else {
-            // init buffers
-            let mut oit_layers_buffer = StorageBuffer::default();
-            oit_layers_buffer.set(vec![UVec2::ZERO; size * OIT_LAYERS]);
-            oit_layers_buffer.write_buffer(&render_device, &render_queue);
+                // The pipelines are specialized on the layer count so it can't be debounced
+                if same_layout {
+                    if buffer.pending_size != size {
+                        buffer.pending_size = size;
+                        buffer.pending_frames = 0;
+                    }
+                    if buffer.pending_frames < settings.resize_debounce_frames {
+                        buffer.pending_frames += 1;
+                        continue;
+                    }
+                }
 
-            let mut oit_layer_ids_buffer = StorageBuffer::default();
-            oit_layer_ids_buffer.set(vec![0; size]);
-            oit_layer_ids_buffer.write_buffer(&render_device, &render_queue);
+                if shrink {
+                    size
+                } else {
+                    // The layer count can change without the target getting bigger
+                    size.max(buffer.size)
+                }
+            }
+            None => size,
+        };
+        // The rounded size can go over the limits of the device when the exact one doesn't
+        let size = Some(settings.growth.capacity(size))
+            .filter(|capacity| OitBuffer::fits_limits(&render_device, *capacity, layers, nodes))
+            .unwrap_or(size);
+        if !OitBuffer::fits_limits(&render_device, size, layers, nodes) {
+            buffers.remove(&entity);
+            if over_limits.insert(entity) {
+                warn!(
+                    "The OIT buffers of {entity:?} are bigger than the device supports, reduce \
+                     OitCamera::layers or the size of the target"
+                );
+            }
+            continue;
+        }
+        over_limits.remove(&entity);
 
-            buffers.insert(entity, (size, oit_layers_buffer, oit_layer_ids_buffer));
+        if let Some(memory_budget) = settings.memory_budget {
+            let used: u64 = buffers
+                .iter()
+                .filter(|(buffer_entity, _)| **buffer_entity != entity)
+                .map(|(_, buffer)| buffer.byte_size())
+                .sum();
+            if used + OitBuffer::required_byte_size(size, layers, nodes) > memory_budget {
+                // The old buffers are too small for the new target so they can't be used either
+                buffers.remove(&entity);
+                if over_budget.insert(entity) {
+                    warn!("The OIT buffers of {entity:?} would go over the memory budget");
+                }
+                continue;
+            }
         }
+        over_budget.remove(&entity);
+
+        buffers.insert(entity, OitBuffer::new(&render_device, size, layers, nodes));
     }
 }
@@ -0,0 +1,177 @@
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Buffer, BufferDescriptor, BufferUsages, MapMode},
+        renderer::{RenderDevice, RenderQueue},
+    },
+    utils::HashMap,
+};
+
+use crate::{pipeline::OitBuffers, OitCamera, OitMethod};
+
+/// The size of the atomic counter at the start of the node pool, including the padding needed
+/// to align the nodes.
+pub(crate) const OIT_NODE_POOL_HEADER_SIZE: usize = 8;
+/// The size of a node of the linked lists, the packed layer and the index of the next node.
+pub(crate) const OIT_NODE_SIZE: usize = 16;
+
+const READBACK_IDLE: u8 = 0;
+const READBACK_COPIED: u8 = 1;
+const READBACK_MAPPING: u8 = 2;
+const READBACK_MAPPED: u8 = 3;
+
+/// Reads back the node pool counter of a camera using [`OitMethod::LinkedList`], or the
+/// contention flag of [`OitMethod::MultiLayerAlphaBlending`].
+///
+/// The counter keeps counting past the size of the pool so it's the number of nodes the frame
+/// needed. Mapping is asynchronous so the value is a few frames late.
+pub struct OitNodeCounterReadback {
+    pub buffer: Buffer,
+    state: Arc<AtomicU8>,
+    /// Whether the last read value overflowed the pool or had contention, to only log once
+    overflowed: bool,
+}
+
+impl OitNodeCounterReadback {
+    fn new(render_device: &RenderDevice) -> Self {
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("oit_node_counter_readback_buffer"),
+            size: std::mem::size_of::<u32>() as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            buffer,
+            state: Arc::new(AtomicU8::new(READBACK_IDLE)),
+            overflowed: false,
+        }
+    }
+
+    /// Whether the counter can be copied in the readback buffer this frame.
+    ///
+    /// This marks the copy as done so it needs to be recorded if it returns true.
+    pub fn start_copy(&self) -> bool {
+        self.state
+            .compare_exchange(
+                READBACK_IDLE,
+                READBACK_COPIED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct OitNodeCounterReadbacks(pub HashMap<Entity, OitNodeCounterReadback>);
+
+/// Resets the node pools and the MLAB contention flags for the new frame, and reports the pools
+/// that overflowed and the MLAB fragments that were blended without sorting.
+pub fn prepare_node_pools(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    buffers: Res<OitBuffers>,
+    cameras: Query<(Entity, &OitCamera)>,
+    mut readbacks: ResMut<OitNodeCounterReadbacks>,
+) {
+    readbacks.retain(|entity, _| buffers.contains_key(entity));
+
+    for (entity, oit_camera) in &cameras {
+        let mlab = match oit_camera.method {
+            OitMethod::LinkedList { .. } => false,
+            OitMethod::MultiLayerAlphaBlending => true,
+            _ => {
+                readbacks.remove(&entity);
+                continue;
+            }
+        };
+        let Some(buffer) = buffers.get(&entity) else {
+            continue;
+        };
+        // The buffers can still have the layout of the previous method
+        if (buffer.nodes > 0) == mlab {
+            continue;
+        }
+
+        // The counter is the first u32 of the pool, the MLAB flag is after the last layer
+        render_queue.write_buffer(
+            &buffer.layers_buffer,
+            buffer.status_offset(),
+            &0u32.to_le_bytes(),
+        );
+
+        let readback = readbacks
+            .entry(entity)
+            .or_insert_with(|| OitNodeCounterReadback::new(&render_device));
+        if readback.state.load(Ordering::Acquire) != READBACK_MAPPED {
+            continue;
+        }
+
+        let status = {
+            let data = readback.buffer.slice(..).get_mapped_range();
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize
+        };
+        readback.buffer.unmap();
+        readback.state.store(READBACK_IDLE, Ordering::Release);
+
+        let overflowed = if mlab {
+            status != 0
+        } else {
+            status > buffer.nodes
+        };
+        if overflowed && !readback.overflowed {
+            if mlab {
+                warn!(
+                    "Some transparent fragments of {entity:?} couldn't take the lock of the MLAB \
+                     layers and were blended without sorting, see OitDebugView::Overflow"
+                );
+            } else {
+                warn!(
+                    "The OIT node pool of {entity:?} overflowed, {status} nodes were needed but it \
+                     only has {}. Some transparent fragments were dropped.",
+                    buffer.nodes
+                );
+            }
+        }
+        readback.overflowed = overflowed;
+    }
+}
+
+/// Maps the readback buffers that got the counter copied in them this frame.
+///
+/// This needs to run after the commands of the frame got submitted. wgpu runs the callbacks when
+/// the device gets polled, which happens on every submit.
+pub fn map_node_counter_readbacks(
+    render_device: Res<RenderDevice>,
+    readbacks: Res<OitNodeCounterReadbacks>,
+) {
+    for readback in readbacks.values() {
+        if readback
+            .state
+            .compare_exchange(
+                READBACK_COPIED,
+                READBACK_MAPPING,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            continue;
+        }
+
+        let state = readback.state.clone();
+        render_device.map_buffer(&readback.buffer.slice(..), MapMode::Read, move |result| {
+            let next = if result.is_ok() {
+                READBACK_MAPPED
+            } else {
+                READBACK_IDLE
+            };
+            state.store(next, Ordering::Release);
+        });
+    }
+}
@@ -1,92 +1,650 @@
+use std::{hash::Hash, marker::PhantomData};
+
 use bevy::{
-    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    core_pipeline::{
+        core_3d::Transparent3d,
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    ecs::{
+        query::ROQueryItem,
+        system::{
+            lifetimeless::{Read, SRes},
+            SystemParamItem,
+        },
+    },
+    pbr::{
+        DrawMesh, EnvironmentMapLight, MeshUniform, ScreenSpaceAmbientOcclusionSettings,
+        SetMeshBindGroup, SetMeshViewBindGroup, Shadow,
+    },
     prelude::*,
     reflect::{TypePath, TypeUuid},
     render::{
-        render_asset::{PrepareAssetError, RenderAsset, RenderAssetPlugin, RenderAssets},
-        render_resource::{AsBindGroup, BindGroup, ShaderType},
+        mesh::MeshVertexBufferLayout,
+        render_asset::{PrepareAssetSet, RenderAssets},
+        render_phase::{
+            AddRenderCommand, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult,
+            RenderPhase, SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::{
+            AsBindGroup, AsBindGroupError, AsBindGroupShaderType, BindGroup, PipelineCache,
+            RenderPipelineDescriptor, ShaderRef, ShaderType, SpecializedMeshPipelineError,
+            SpecializedMeshPipelines,
+        },
         renderer::RenderDevice,
         texture::FallbackImage,
-        Extract, RenderApp,
+        view::{ExtractedView, VisibleEntities},
+        Extract, Render, RenderApp, RenderSet,
     },
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    pipeline::{oit_mesh_key, oit_view_key, OitDrawPipeline, OitKey},
+    shadow::{queue_oit_shadows, DrawOitShadow, OitShadowKey, OitShadowPipeline},
+    OitCamera, OitMethod, OitPhaseItem, SetOitLayersBindGroup,
+    STANDARD_OIT_MATERIAL_SHADOW_SHADER_HANDLE,
 };
 
-use crate::pipeline::OitDrawPipeline;
+/// Materials used to render meshes in the OIT phase.
+///
+/// This mirrors bevy's `Material` trait. The fragment shader is expected to import
+/// `bevy_oit::oit_draw` and return the `OitFragmentOutput` of `oit_draw()` instead of writing
+/// the color directly. `bevy_oit::oit_draw` must be the first import of the shader.
+///
+/// ```wgsl
+/// #import bevy_oit::oit_draw oit_draw, OitFragmentOutput
+/// #import bevy_pbr::mesh_vertex_output MeshVertexOutput
+///
+/// @group(1) @binding(0)
+/// var<uniform> color: vec4<f32>;
+///
+/// @fragment
+/// fn fragment(
+///     @builtin(sample_mask) sample_mask: u32,
+///     mesh: MeshVertexOutput,
+/// ) -> OitFragmentOutput {
+///     return oit_draw(mesh.position, color, sample_mask);
+/// }
+/// ```
+pub trait OitMaterial:
+    AsBindGroup + Send + Sync + Clone + TypeUuid + TypePath + Sized + 'static
+{
+    /// Returns this material's vertex shader. If [`ShaderRef::Default`] is returned, the default
+    /// mesh vertex shader will be used.
+    #[must_use]
+    fn vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Returns this material's fragment shader. If [`ShaderRef::Default`] is returned, the
+    /// [`StandardOitMaterial`] fragment shader will be used.
+    #[must_use]
+    fn fragment_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Customizes the default [`RenderPipelineDescriptor`] for a specific entity using the
+    /// entity's [`OitMaterialKey`] and [`MeshVertexBufferLayout`] as input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pipeline can't be specialized for the mesh layout.
+    #[allow(unused_variables)]
+    #[inline]
+    fn specialize(
+        pipeline: &OitDrawPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: OitMaterialKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        Ok(())
+    }
+
+    /// Returns this material's fragment shader for the shadow maps. If [`ShaderRef::Default`] is
+    /// returned, the meshes cast opaque shadows.
+    ///
+    /// The shader receives the output of bevy's prepass vertex shader. It can discard fragments
+    /// to let some light through, the shadow maps only store a depth so they can't be colored.
+    #[must_use]
+    fn shadow_fragment_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Customizes the default [`RenderPipelineDescriptor`] of the shadow pipeline for a specific
+    /// entity using the entity's [`OitShadowKey`] and [`MeshVertexBufferLayout`] as input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pipeline can't be specialized for the mesh layout.
+    #[allow(unused_variables)]
+    #[inline]
+    fn specialize_shadow(
+        pipeline: &OitShadowPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: OitShadowKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        Ok(())
+    }
+}
+
+/// Adds the necessary ECS resources and render logic to render entities using the given
+/// [`OitMaterial`] asset type.
+pub struct OitMaterialPlugin<M: OitMaterial> {
+    /// Controls if the meshes using this material are rendered in the shadow maps of the
+    /// lights. Meshes with a [`NotShadowCaster`](bevy::pbr::NotShadowCaster) never are.
+    pub shadows_enabled: bool,
+    pub _marker: PhantomData<M>,
+}
 
-pub struct OitMaterialPlugin;
-impl Plugin for OitMaterialPlugin {
+impl<M: OitMaterial> Default for OitMaterialPlugin<M> {
+    fn default() -> Self {
+        Self {
+            shadows_enabled: true,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<M: OitMaterial> Plugin for OitMaterialPlugin<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
     fn build(&self, app: &mut App) {
-        app.add_asset::<OitMaterial>()
-            .add_plugins(RenderAssetPlugin::<OitMaterial>::default());
+        app.add_asset::<M>();
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ExtractedOitMaterials<M>>()
+            .init_resource::<RenderOitMaterials<M>>()
+            .init_resource::<SpecializedMeshPipelines<OitDrawPipeline<M>>>()
+            .add_render_command::<OitPhaseItem, DrawOit<M>>()
+            .add_render_command::<Transparent3d, DrawOit<M>>()
+            .add_systems(
+                ExtractSchedule,
+                (
+                    extract_oit_materials::<M>,
+                    extract_oit_material_handles::<M>,
+                ),
+            )
+            .add_systems(
+                Render,
+                (
+                    prepare_oit_materials::<M>
+                        .in_set(RenderSet::Prepare)
+                        .after(PrepareAssetSet::PreAssetPrepare),
+                    queue_mesh_oit_phase::<M>.in_set(RenderSet::Queue),
+                ),
+            );
 
+        if self.shadows_enabled {
+            render_app
+                .init_resource::<SpecializedMeshPipelines<OitShadowPipeline<M>>>()
+                .add_render_command::<Shadow, DrawOitShadow<M>>()
+                .add_systems(Render, queue_oit_shadows::<M>.in_set(RenderSet::Queue));
+        }
+    }
+
+    fn finish(&self, app: &mut App) {
         let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
             return;
         };
+        render_app.init_resource::<OitDrawPipeline<M>>();
+        if self.shadows_enabled {
+            render_app.init_resource::<OitShadowPipeline<M>>();
+        }
+    }
+}
+
+/// A key uniquely identifying a specialized [`OitDrawPipeline`].
+pub struct OitMaterialKey<M: OitMaterial> {
+    pub oit_key: OitKey,
+    pub bind_group_data: M::Data,
+}
+
+impl<M: OitMaterial> Eq for OitMaterialKey<M> where M::Data: PartialEq {}
+
+impl<M: OitMaterial> PartialEq for OitMaterialKey<M>
+where
+    M::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.oit_key == other.oit_key && self.bind_group_data == other.bind_group_data
+    }
+}
+
+impl<M: OitMaterial> Clone for OitMaterialKey<M>
+where
+    M::Data: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            oit_key: self.oit_key,
+            bind_group_data: self.bind_group_data.clone(),
+        }
+    }
+}
 
-        render_app.add_systems(ExtractSchedule, extract_oit_material);
+impl<M: OitMaterial> Hash for OitMaterialKey<M>
+where
+    M::Data: Hash,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.oit_key.hash(state);
+        self.bind_group_data.hash(state);
     }
 }
 
-#[derive(TypeUuid, TypePath, Debug, Clone, ShaderType, AsBindGroup)]
+/// The shading model used by a [`StandardOitMaterial`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OitShading {
+    /// Uses the base color as is.
+    Unlit,
+    /// Interpolates between a warm and a cool color based on a fixed light direction.
+    #[default]
+    Gooch,
+    /// Uses the lights of the scene like a [`StandardMaterial`] with the default properties.
+    Pbr,
+}
+
+/// The default [`OitMaterial`]
+///
+/// The textures are sampled with the first UVs of the mesh and the base color is multiplied by
+/// the vertex colors when the mesh has them.
+#[derive(TypeUuid, TypePath, Debug, Clone, AsBindGroup)]
 #[uuid = "eb8e4d86-5e76-57cd-9eb3-00a2ad641233"]
-pub struct OitMaterial {
-    #[uniform(0)]
+#[bind_group_data(StandardOitMaterialKey)]
+#[uniform(0, StandardOitMaterialUniform)]
+pub struct StandardOitMaterial {
     pub base_color: Color,
+    /// Multiplied with the base color, including the alpha.
+    #[texture(1)]
+    #[sampler(2)]
+    pub base_color_texture: Option<Handle<Image>>,
+    /// Light emitted by the surface, added to the shaded color. The alpha is ignored.
+    pub emissive: Color,
+    /// Multiplied with the emissive color.
+    #[texture(3)]
+    #[sampler(4)]
+    pub emissive_texture: Option<Handle<Image>>,
+    /// Fragments with an alpha below this value are discarded instead of being stored in the
+    /// OIT layers, the others keep their alpha.
+    ///
+    /// This is useful to cut out the shape of textured foliage or decals without using layers
+    /// for the fully transparent parts.
+    pub alpha_cutoff: Option<f32>,
+    pub shading: OitShading,
+}
+
+impl Default for StandardOitMaterial {
+    fn default() -> Self {
+        Self {
+            base_color: Color::WHITE.with_a(0.5),
+            base_color_texture: None,
+            emissive: Color::BLACK,
+            emissive_texture: None,
+            alpha_cutoff: None,
+            shading: OitShading::default(),
+        }
+    }
+}
+
+/// The GPU representation of the uniform data of a [`StandardOitMaterial`].
+#[derive(Clone, Default, ShaderType)]
+pub struct StandardOitMaterialUniform {
+    pub base_color: Vec4,
+    pub emissive: Vec4,
+    pub alpha_cutoff: f32,
+}
+
+impl AsBindGroupShaderType<StandardOitMaterialUniform> for StandardOitMaterial {
+    fn as_bind_group_shader_type(
+        &self,
+        _images: &RenderAssets<Image>,
+    ) -> StandardOitMaterialUniform {
+        StandardOitMaterialUniform {
+            base_color: self.base_color.as_linear_rgba_f32().into(),
+            emissive: self.emissive.as_linear_rgba_f32().into(),
+            alpha_cutoff: self.alpha_cutoff.unwrap_or(0.0),
+        }
+    }
+}
+
+/// The bind group data of a [`StandardOitMaterial`] used to specialize its pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StandardOitMaterialKey {
+    pub shading: OitShading,
+    pub base_color_texture: bool,
+    pub emissive_texture: bool,
+    pub alpha_mask: bool,
+}
+
+impl From<&StandardOitMaterial> for StandardOitMaterialKey {
+    fn from(material: &StandardOitMaterial) -> Self {
+        Self {
+            shading: material.shading,
+            base_color_texture: material.base_color_texture.is_some(),
+            emissive_texture: material.emissive_texture.is_some(),
+            alpha_mask: material.alpha_cutoff.is_some(),
+        }
+    }
+}
+
+impl OitMaterial for StandardOitMaterial {
+    fn specialize(
+        _pipeline: &OitDrawPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: OitMaterialKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let shading = match key.bind_group_data.shading {
+            OitShading::Unlit => "OIT_SHADING_UNLIT",
+            OitShading::Gooch => "OIT_SHADING_GOOCH",
+            OitShading::Pbr => "OIT_SHADING_PBR",
+        };
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            fragment.shader_defs.push(shading.into());
+            // The mesh pipeline adds VERTEX_UVS and VERTEX_COLORS based on the mesh layout
+            if key.bind_group_data.base_color_texture {
+                fragment.shader_defs.push("OIT_BASE_COLOR_TEXTURE".into());
+            }
+            if key.bind_group_data.emissive_texture {
+                fragment.shader_defs.push("OIT_EMISSIVE_TEXTURE".into());
+            }
+            if key.bind_group_data.alpha_mask {
+                fragment.shader_defs.push("OIT_ALPHA_MASK".into());
+            }
+        }
+        Ok(())
+    }
+
+    fn shadow_fragment_shader() -> ShaderRef {
+        STANDARD_OIT_MATERIAL_SHADOW_SHADER_HANDLE.typed().into()
+    }
+
+    fn specialize_shadow(
+        _pipeline: &OitShadowPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: OitShadowKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            if key.bind_group_data.base_color_texture {
+                fragment.shader_defs.push("OIT_BASE_COLOR_TEXTURE".into());
+            }
+            if key.bind_group_data.alpha_mask {
+                fragment.shader_defs.push("OIT_ALPHA_MASK".into());
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Bundle, Clone, Default)]
-pub struct OitMaterialMeshBundle {
+#[derive(Bundle, Clone)]
+pub struct OitMaterialMeshBundle<M: OitMaterial> {
     pub mesh: Handle<Mesh>,
-    pub material: Handle<OitMaterial>,
+    pub material: Handle<M>,
     pub transform: Transform,
     pub global_transform: GlobalTransform,
     pub visibility: Visibility,
     pub computed_visibility: ComputedVisibility,
 }
 
-pub struct GpuOitMaterial {
+impl<M: OitMaterial> Default for OitMaterialMeshBundle<M> {
+    fn default() -> Self {
+        Self {
+            mesh: Handle::default(),
+            material: Handle::default(),
+            transform: Transform::default(),
+            global_transform: GlobalTransform::default(),
+            visibility: Visibility::default(),
+            computed_visibility: ComputedVisibility::default(),
+        }
+    }
+}
+
+/// Data prepared for an [`OitMaterial`] instance.
+pub struct PreparedOitMaterial<M: OitMaterial> {
     pub bind_group: BindGroup,
+    pub key: M::Data,
 }
 
-impl RenderAsset for OitMaterial {
-    type ExtractedAsset = Self;
+#[derive(Resource)]
+pub struct ExtractedOitMaterials<M: OitMaterial> {
+    extracted: Vec<(Handle<M>, M)>,
+    removed: Vec<Handle<M>>,
+}
 
-    type PreparedAsset = GpuOitMaterial;
+impl<M: OitMaterial> Default for ExtractedOitMaterials<M> {
+    fn default() -> Self {
+        Self {
+            extracted: Vec::default(),
+            removed: Vec::default(),
+        }
+    }
+}
 
-    type Param = (
-        SRes<RenderDevice>,
-        SRes<OitDrawPipeline>,
-        SRes<RenderAssets<Image>>,
-        SRes<FallbackImage>,
-    );
+/// Stores all prepared representations of [`OitMaterial`] assets for as long as they exist.
+#[derive(Resource, Deref, DerefMut)]
+pub struct RenderOitMaterials<M: OitMaterial>(pub HashMap<Handle<M>, PreparedOitMaterial<M>>);
 
-    fn extract_asset(&self) -> Self::ExtractedAsset {
-        self.clone()
+impl<M: OitMaterial> Default for RenderOitMaterials<M> {
+    fn default() -> Self {
+        Self(HashMap::default())
     }
+}
 
-    fn prepare_asset(
-        extracted_asset: Self::ExtractedAsset,
-        (render_device, pipeline, images, fallback_image): &mut SystemParamItem<Self::Param>,
-    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
-        let prepared_bind_group = extracted_asset
-            .as_bind_group(
-                &pipeline.oit_material_bind_group_layout,
-                render_device,
-                images,
-                fallback_image,
-            )
-            .map_err(|_| PrepareAssetError::RetryNextUpdate(extracted_asset))?;
-        Ok(GpuOitMaterial {
-            bind_group: prepared_bind_group.bind_group,
-        })
+fn extract_oit_materials<M: OitMaterial>(
+    mut commands: Commands,
+    mut events: Extract<EventReader<AssetEvent<M>>>,
+    assets: Extract<Res<Assets<M>>>,
+) {
+    let mut changed_assets = HashSet::default();
+    let mut removed = Vec::new();
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed_assets.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                changed_assets.remove(handle);
+                removed.push(handle.clone_weak());
+            }
+        }
+    }
+
+    let mut extracted = Vec::new();
+    for handle in changed_assets.drain() {
+        if let Some(asset) = assets.get(&handle) {
+            extracted.push((handle, asset.clone()));
+        }
     }
+
+    commands.insert_resource(ExtractedOitMaterials { extracted, removed });
 }
 
-fn extract_oit_material(
+fn extract_oit_material_handles<M: OitMaterial>(
     mut commands: Commands,
-    oit_materials: Extract<Query<(Entity, &Handle<OitMaterial>)>>,
+    oit_materials: Extract<Query<(Entity, &Handle<M>)>>,
 ) {
     for (entity, material) in &oit_materials {
         commands.get_or_spawn(entity).insert(material.clone());
     }
 }
+
+fn prepare_oit_materials<M: OitMaterial>(
+    mut prepare_next_frame: Local<Vec<(Handle<M>, M)>>,
+    mut extracted_assets: ResMut<ExtractedOitMaterials<M>>,
+    mut render_materials: ResMut<RenderOitMaterials<M>>,
+    render_device: Res<RenderDevice>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    pipeline: Res<OitDrawPipeline<M>>,
+) {
+    for removed in std::mem::take(&mut extracted_assets.removed) {
+        render_materials.remove(&removed);
+    }
+
+    let queued_assets = std::mem::take(&mut *prepare_next_frame);
+    let extracted = std::mem::take(&mut extracted_assets.extracted);
+    for (handle, material) in queued_assets.into_iter().chain(extracted) {
+        match material.as_bind_group(
+            &pipeline.oit_material_bind_group_layout,
+            &render_device,
+            &images,
+            &fallback_image,
+        ) {
+            Ok(prepared) => {
+                render_materials.insert(
+                    handle,
+                    PreparedOitMaterial {
+                        bind_group: prepared.bind_group,
+                        key: prepared.data,
+                    },
+                );
+            }
+            Err(AsBindGroupError::RetryNextUpdate) => {
+                prepare_next_frame.push((handle, material));
+            }
+        }
+    }
+}
+
+pub(crate) struct SetOitMaterialBindGroup<M: OitMaterial, const I: usize>(PhantomData<M>);
+impl<P: PhaseItem, M: OitMaterial, const I: usize> RenderCommand<P>
+    for SetOitMaterialBindGroup<M, I>
+{
+    type Param = SRes<RenderOitMaterials<M>>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Read<Handle<M>>;
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        material_handle: ROQueryItem<'w, Self::ItemWorldQuery>,
+        materials: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(material) = materials.into_inner().get(material_handle) else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, &material.bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+pub(crate) type DrawOit<M> = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetOitMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    SetOitLayersBindGroup<3>,
+    DrawMesh,
+);
+
+#[allow(clippy::too_many_arguments)]
+fn queue_mesh_oit_phase<M: OitMaterial>(
+    draw_functions: Res<DrawFunctions<OitPhaseItem>>,
+    transparent_draw_functions: Res<DrawFunctions<Transparent3d>>,
+    pipeline: Res<OitDrawPipeline<M>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<OitDrawPipeline<M>>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderOitMaterials<M>>,
+    meshes: Query<(Entity, &Handle<Mesh>, &MeshUniform, &Handle<M>)>,
+    images: Res<RenderAssets<Image>>,
+    mut views: Query<(
+        &ExtractedView,
+        &OitCamera,
+        &mut VisibleEntities,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        Option<&EnvironmentMapLight>,
+        Option<&ScreenSpaceAmbientOcclusionSettings>,
+        &mut RenderPhase<OitPhaseItem>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
+    msaa: Res<Msaa>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_function = draw_functions.read().id::<DrawOit<M>>();
+    let transparent_draw_function = transparent_draw_functions.read().id::<DrawOit<M>>();
+
+    for (
+        view,
+        oit_camera,
+        visible_entities,
+        tonemapping,
+        dither,
+        environment_map,
+        ssao,
+        mut oit_phase,
+        mut transparent_phase,
+    ) in &mut views
+    {
+        let view_matrix = view.transform.compute_matrix();
+        let inv_view_row_2 = view_matrix.inverse().row(2);
+
+        let view_key = oit_view_key(
+            view,
+            *msaa,
+            &images,
+            tonemapping,
+            dither,
+            environment_map,
+            ssao,
+        );
+
+        for visible_entity in visible_entities.entities.iter().copied() {
+            let Ok((entity, mesh_handle, mesh_uniform, material_handle)) =
+                meshes.get(visible_entity)
+            else {
+                continue;
+            };
+            let Some(mesh) = render_meshes.get(mesh_handle) else {
+                continue;
+            };
+            let Some(material) = render_materials.get(material_handle) else {
+                continue;
+            };
+
+            let mesh_key = oit_mesh_key(mesh, view_key);
+            let key = OitMaterialKey {
+                oit_key: OitKey {
+                    mesh_key,
+                    method: oit_camera.method,
+                    layers: oit_camera.layers,
+                    tail_blend: oit_camera.tail_blend,
+                    k_buffer: oit_camera.k_buffer,
+                },
+                bind_group_data: material.key.clone(),
+            };
+            let Ok(pipeline) = pipelines.specialize(&pipeline_cache, &pipeline, key, &mesh.layout)
+            else {
+                continue;
+            };
+
+            let distance = inv_view_row_2.dot(mesh_uniform.transform.col(3));
+            if oit_camera.method == OitMethod::TransparentPhase {
+                transparent_phase.add(Transparent3d {
+                    entity,
+                    pipeline,
+                    draw_function: transparent_draw_function,
+                    distance,
+                });
+            } else {
+                oit_phase.add(OitPhaseItem {
+                    entity,
+                    pipeline,
+                    draw_function,
+                    distance,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,508 @@
+use std::{hash::Hash, marker::PhantomData};
+
+use bevy::{
+    core_pipeline::{
+        core_2d::Transparent2d,
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    ecs::{
+        query::ROQueryItem,
+        system::{
+            lifetimeless::{Read, SRes},
+            SystemParamItem,
+        },
+    },
+    prelude::*,
+    reflect::{TypePath, TypeUuid},
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::{PrepareAssetSet, RenderAssets},
+        render_phase::{
+            AddRenderCommand, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult,
+            RenderPhase, SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::{
+            AsBindGroup, AsBindGroupError, BindGroup, PipelineCache, RenderPipelineDescriptor,
+            ShaderRef, SpecializedMeshPipelineError, SpecializedMeshPipelines,
+        },
+        renderer::RenderDevice,
+        texture::FallbackImage,
+        view::{ExtractedView, VisibleEntities},
+        Extract, Render, RenderApp, RenderSet,
+    },
+    sprite::{
+        DrawMesh2d, Mesh2dHandle, Mesh2dPipelineKey, Mesh2dUniform, SetMesh2dBindGroup,
+        SetMesh2dViewBindGroup,
+    },
+    utils::{FloatOrd, HashMap, HashSet},
+};
+
+use crate::{
+    pipeline::{oit_view_2d_key, OitDraw2dPipeline, OitKey},
+    OitCamera, OitMethod, OitPhaseItem2d, SetOitLayersBindGroup,
+};
+
+/// Materials used to render [`Mesh2dHandle`] meshes in the OIT phase of 2d cameras.
+///
+/// This mirrors bevy's `Material2d` trait and works like
+/// [`OitMaterial`](crate::material::OitMaterial). The fragment shader receives the output of
+/// bevy's mesh2d vertex shader and returns the `OitFragmentOutput` of `oit_draw()`.
+///
+/// ```wgsl
+/// #import bevy_oit::oit_draw oit_draw, OitFragmentOutput
+/// #import bevy_sprite::mesh2d_vertex_output MeshVertexOutput
+///
+/// @group(1) @binding(0)
+/// var<uniform> color: vec4<f32>;
+///
+/// @fragment
+/// fn fragment(
+///     @builtin(sample_mask) sample_mask: u32,
+///     mesh: MeshVertexOutput,
+/// ) -> OitFragmentOutput {
+///     return oit_draw(mesh.position, color, sample_mask);
+/// }
+/// ```
+pub trait OitMaterial2d:
+    AsBindGroup + Send + Sync + Clone + TypeUuid + TypePath + Sized + 'static
+{
+    /// Returns this material's vertex shader. If [`ShaderRef::Default`] is returned, the default
+    /// mesh2d vertex shader will be used.
+    #[must_use]
+    fn vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Returns this material's fragment shader. If [`ShaderRef::Default`] is returned, the
+    /// [`ColorOitMaterial`] fragment shader will be used.
+    #[must_use]
+    fn fragment_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Customizes the default [`RenderPipelineDescriptor`] for a specific entity using the
+    /// entity's [`OitMaterial2dKey`] and [`MeshVertexBufferLayout`] as input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pipeline can't be specialized for the mesh layout.
+    #[allow(unused_variables)]
+    #[inline]
+    fn specialize(
+        pipeline: &OitDraw2dPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: OitMaterial2dKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        Ok(())
+    }
+}
+
+/// Adds the necessary ECS resources and render logic to render 2d meshes using the given
+/// [`OitMaterial2d`] asset type.
+pub struct OitMaterial2dPlugin<M: OitMaterial2d>(PhantomData<M>);
+
+impl<M: OitMaterial2d> Default for OitMaterial2dPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M: OitMaterial2d> Plugin for OitMaterial2dPlugin<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    fn build(&self, app: &mut App) {
+        app.add_asset::<M>();
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ExtractedOitMaterials2d<M>>()
+            .init_resource::<RenderOitMaterials2d<M>>()
+            .init_resource::<SpecializedMeshPipelines<OitDraw2dPipeline<M>>>()
+            .add_render_command::<OitPhaseItem2d, DrawOit2d<M>>()
+            .add_render_command::<Transparent2d, DrawOit2d<M>>()
+            .add_systems(
+                ExtractSchedule,
+                (
+                    extract_oit_materials_2d::<M>,
+                    extract_oit_material_2d_handles::<M>,
+                ),
+            )
+            .add_systems(
+                Render,
+                (
+                    prepare_oit_materials_2d::<M>
+                        .in_set(RenderSet::Prepare)
+                        .after(PrepareAssetSet::PreAssetPrepare),
+                    queue_mesh2d_oit_phase::<M>.in_set(RenderSet::Queue),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.init_resource::<OitDraw2dPipeline<M>>();
+    }
+}
+
+/// A key uniquely identifying a specialized [`OitDraw2dPipeline`].
+pub struct OitMaterial2dKey<M: OitMaterial2d> {
+    pub oit_key: OitKey<Mesh2dPipelineKey>,
+    pub bind_group_data: M::Data,
+}
+
+impl<M: OitMaterial2d> Eq for OitMaterial2dKey<M> where M::Data: PartialEq {}
+
+impl<M: OitMaterial2d> PartialEq for OitMaterial2dKey<M>
+where
+    M::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.oit_key == other.oit_key && self.bind_group_data == other.bind_group_data
+    }
+}
+
+impl<M: OitMaterial2d> Clone for OitMaterial2dKey<M>
+where
+    M::Data: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            oit_key: self.oit_key,
+            bind_group_data: self.bind_group_data.clone(),
+        }
+    }
+}
+
+impl<M: OitMaterial2d> Hash for OitMaterial2dKey<M>
+where
+    M::Data: Hash,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.oit_key.hash(state);
+        self.bind_group_data.hash(state);
+    }
+}
+
+/// The default [`OitMaterial2d`], a color optionally multiplied by a texture like bevy's
+/// `ColorMaterial`.
+///
+/// The color is also multiplied by the vertex colors when the mesh has them.
+#[derive(TypeUuid, TypePath, Debug, Clone, AsBindGroup)]
+#[uuid = "5b1e1a0c-3f4c-4e39-9a52-d1c6f2b0e7a4"]
+#[bind_group_data(ColorOitMaterialKey)]
+pub struct ColorOitMaterial {
+    #[uniform(0)]
+    pub color: Color,
+    #[texture(1)]
+    #[sampler(2)]
+    pub texture: Option<Handle<Image>>,
+}
+
+impl Default for ColorOitMaterial {
+    fn default() -> Self {
+        Self {
+            color: Color::WHITE.with_a(0.5),
+            texture: None,
+        }
+    }
+}
+
+impl From<Color> for ColorOitMaterial {
+    fn from(color: Color) -> Self {
+        Self { color, ..default() }
+    }
+}
+
+/// The bind group data of a [`ColorOitMaterial`] used to specialize its pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ColorOitMaterialKey {
+    pub texture: bool,
+}
+
+impl From<&ColorOitMaterial> for ColorOitMaterialKey {
+    fn from(material: &ColorOitMaterial) -> Self {
+        Self {
+            texture: material.texture.is_some(),
+        }
+    }
+}
+
+impl OitMaterial2d for ColorOitMaterial {
+    fn specialize(
+        _pipeline: &OitDraw2dPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: OitMaterial2dKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            if key.bind_group_data.texture {
+                fragment.shader_defs.push("OIT_TEXTURE".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Bundle, Clone)]
+pub struct OitMaterialMesh2dBundle<M: OitMaterial2d> {
+    pub mesh: Mesh2dHandle,
+    pub material: Handle<M>,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+    pub visibility: Visibility,
+    pub computed_visibility: ComputedVisibility,
+}
+
+impl<M: OitMaterial2d> Default for OitMaterialMesh2dBundle<M> {
+    fn default() -> Self {
+        Self {
+            mesh: Mesh2dHandle::default(),
+            material: Handle::default(),
+            transform: Transform::default(),
+            global_transform: GlobalTransform::default(),
+            visibility: Visibility::default(),
+            computed_visibility: ComputedVisibility::default(),
+        }
+    }
+}
+
+/// Data prepared for an [`OitMaterial2d`] instance.
+pub struct PreparedOitMaterial2d<M: OitMaterial2d> {
+    pub bind_group: BindGroup,
+    pub key: M::Data,
+}
+
+#[derive(Resource)]
+pub struct ExtractedOitMaterials2d<M: OitMaterial2d> {
+    extracted: Vec<(Handle<M>, M)>,
+    removed: Vec<Handle<M>>,
+}
+
+impl<M: OitMaterial2d> Default for ExtractedOitMaterials2d<M> {
+    fn default() -> Self {
+        Self {
+            extracted: Vec::default(),
+            removed: Vec::default(),
+        }
+    }
+}
+
+/// Stores all prepared representations of [`OitMaterial2d`] assets for as long as they exist.
+#[derive(Resource, Deref, DerefMut)]
+pub struct RenderOitMaterials2d<M: OitMaterial2d>(pub HashMap<Handle<M>, PreparedOitMaterial2d<M>>);
+
+impl<M: OitMaterial2d> Default for RenderOitMaterials2d<M> {
+    fn default() -> Self {
+        Self(HashMap::default())
+    }
+}
+
+fn extract_oit_materials_2d<M: OitMaterial2d>(
+    mut commands: Commands,
+    mut events: Extract<EventReader<AssetEvent<M>>>,
+    assets: Extract<Res<Assets<M>>>,
+) {
+    let mut changed_assets = HashSet::default();
+    let mut removed = Vec::new();
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed_assets.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                changed_assets.remove(handle);
+                removed.push(handle.clone_weak());
+            }
+        }
+    }
+
+    let mut extracted = Vec::new();
+    for handle in changed_assets.drain() {
+        if let Some(asset) = assets.get(&handle) {
+            extracted.push((handle, asset.clone()));
+        }
+    }
+
+    commands.insert_resource(ExtractedOitMaterials2d { extracted, removed });
+}
+
+fn extract_oit_material_2d_handles<M: OitMaterial2d>(
+    mut commands: Commands,
+    oit_materials: Extract<Query<(Entity, &Handle<M>)>>,
+) {
+    for (entity, material) in &oit_materials {
+        commands.get_or_spawn(entity).insert(material.clone());
+    }
+}
+
+fn prepare_oit_materials_2d<M: OitMaterial2d>(
+    mut prepare_next_frame: Local<Vec<(Handle<M>, M)>>,
+    mut extracted_assets: ResMut<ExtractedOitMaterials2d<M>>,
+    mut render_materials: ResMut<RenderOitMaterials2d<M>>,
+    render_device: Res<RenderDevice>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    pipeline: Res<OitDraw2dPipeline<M>>,
+) {
+    for removed in std::mem::take(&mut extracted_assets.removed) {
+        render_materials.remove(&removed);
+    }
+
+    let queued_assets = std::mem::take(&mut *prepare_next_frame);
+    let extracted = std::mem::take(&mut extracted_assets.extracted);
+    for (handle, material) in queued_assets.into_iter().chain(extracted) {
+        match material.as_bind_group(
+            &pipeline.oit_material_bind_group_layout,
+            &render_device,
+            &images,
+            &fallback_image,
+        ) {
+            Ok(prepared) => {
+                render_materials.insert(
+                    handle,
+                    PreparedOitMaterial2d {
+                        bind_group: prepared.bind_group,
+                        key: prepared.data,
+                    },
+                );
+            }
+            Err(AsBindGroupError::RetryNextUpdate) => {
+                prepare_next_frame.push((handle, material));
+            }
+        }
+    }
+}
+
+pub(crate) struct SetOitMaterial2dBindGroup<M: OitMaterial2d, const I: usize>(PhantomData<M>);
+impl<P: PhaseItem, M: OitMaterial2d, const I: usize> RenderCommand<P>
+    for SetOitMaterial2dBindGroup<M, I>
+{
+    type Param = SRes<RenderOitMaterials2d<M>>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Read<Handle<M>>;
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        material_handle: ROQueryItem<'w, Self::ItemWorldQuery>,
+        materials: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(material) = materials.into_inner().get(material_handle) else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, &material.bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+pub(crate) type DrawOit2d<M> = (
+    SetItemPipeline,
+    SetMesh2dViewBindGroup<0>,
+    SetOitMaterial2dBindGroup<M, 1>,
+    SetMesh2dBindGroup<2>,
+    SetOitLayersBindGroup<3>,
+    DrawMesh2d,
+);
+
+#[allow(clippy::too_many_arguments)]
+fn queue_mesh2d_oit_phase<M: OitMaterial2d>(
+    draw_functions: Res<DrawFunctions<OitPhaseItem2d>>,
+    transparent_draw_functions: Res<DrawFunctions<Transparent2d>>,
+    pipeline: Res<OitDraw2dPipeline<M>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<OitDraw2dPipeline<M>>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderOitMaterials2d<M>>,
+    meshes: Query<(Entity, &Mesh2dHandle, &Mesh2dUniform, &Handle<M>)>,
+    mut views: Query<(
+        &ExtractedView,
+        &OitCamera,
+        &VisibleEntities,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        &mut RenderPhase<OitPhaseItem2d>,
+        &mut RenderPhase<Transparent2d>,
+    )>,
+    msaa: Res<Msaa>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_function = draw_functions.read().id::<DrawOit2d<M>>();
+    let transparent_draw_function = transparent_draw_functions.read().id::<DrawOit2d<M>>();
+
+    for (
+        view,
+        oit_camera,
+        visible_entities,
+        tonemapping,
+        dither,
+        mut oit_phase,
+        mut transparent_phase,
+    ) in &mut views
+    {
+        // Depth peeling needs the depth texture of the view, the 2d cameras fall back to
+        // weighted blended OIT before this runs
+        if let OitMethod::DepthPeeling { .. } = oit_camera.method {
+            continue;
+        }
+
+        let view_key = oit_view_2d_key(view, *msaa, tonemapping, dither);
+
+        for visible_entity in visible_entities.entities.iter().copied() {
+            let Ok((entity, mesh_handle, mesh_uniform, material_handle)) =
+                meshes.get(visible_entity)
+            else {
+                continue;
+            };
+            let Some(mesh) = render_meshes.get(&mesh_handle.0) else {
+                continue;
+            };
+            let Some(material) = render_materials.get(material_handle) else {
+                continue;
+            };
+
+            let key = OitMaterial2dKey {
+                oit_key: OitKey {
+                    mesh_key: view_key
+                        | Mesh2dPipelineKey::from_primitive_topology(mesh.primitive_topology),
+                    method: oit_camera.method,
+                    layers: oit_camera.layers,
+                    tail_blend: oit_camera.tail_blend,
+                    k_buffer: oit_camera.k_buffer,
+                },
+                bind_group_data: material.key.clone(),
+            };
+            let Ok(pipeline) = pipelines.specialize(&pipeline_cache, &pipeline, key, &mesh.layout)
+            else {
+                continue;
+            };
+
+            let sort_key = FloatOrd(mesh_uniform.transform.w_axis.z);
+            if oit_camera.method == OitMethod::TransparentPhase {
+                transparent_phase.add(Transparent2d {
+                    entity,
+                    pipeline,
+                    draw_function: transparent_draw_function,
+                    sort_key,
+                    batch_range: None,
+                });
+            } else {
+                oit_phase.add(OitPhaseItem2d {
+                    entity,
+                    pipeline,
+                    draw_function,
+                    sort_key,
+                });
+            }
+        }
+    }
+}
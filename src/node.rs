@@ -1,13 +1,17 @@
+use std::{marker::PhantomData, ops::Range};
+
 use bevy::{
+    core_pipeline::{core_3d::Transparent3d, prepass::ViewPrepassTextures},
     ecs::query::QueryItem,
+    pbr::PreviousViewProjectionUniformOffset,
     prelude::*,
     render::{
         camera::ExtractedCamera,
         render_graph::{NodeRunError, RenderGraphContext, ViewNode},
-        render_phase::RenderPhase,
+        render_phase::{PhaseItem, RenderPhase},
         render_resource::{
-            LoadOp, Operations, PipelineCache, RenderPassDepthStencilAttachment,
-            RenderPassDescriptor,
+            BindGroup, LoadOp, Operations, PipelineCache, RenderPassColorAttachment,
+            RenderPassDepthStencilAttachment, RenderPassDescriptor, RenderPipeline,
         },
         renderer::RenderContext,
         view::{ViewDepthTexture, ViewTarget, ViewUniformOffset},
@@ -15,88 +19,651 @@ use bevy::{
 };
 
 use crate::{
-    pipeline::{OitRenderPipelineId, OitRenderViewBindGroup},
-    OitLayersBindGroup, OitPhaseItem,
+    depth_output::OitDepthOutputTexture,
+    depth_peeling::{
+        OitDepthPeelingBindGroups, OitDepthPeelingPipelineIds, OitDepthPeelingTextures,
+    },
+    linked_list::OitNodeCounterReadbacks,
+    pipeline::{
+        OitBuffers, OitDepthOutputPipelineId, OitMotionVectorsPipelineId,
+        OitMotionVectorsViewBindGroup, OitRenderPipelineId, OitRenderViewBindGroup,
+    },
+    transparent_3d::{OitDepthSliceBindGroup, OitResolveSlices},
+    weighted_blended::{
+        OitCompositePipelineId, OitWeightedBlendedBindGroup, OitWeightedBlendedTextures,
+    },
+    OitCamera, OitLayersBindGroup, OitMethod, OitPhaseItem, OitPhaseItem2d,
 };
 
-#[derive(Default)]
-pub struct OitNode;
-impl OitNode {
+/// Renders the OIT phase of the views using the phase item `P`, [`OitPhaseItem`] for the 3d
+/// cameras and [`OitPhaseItem2d`] for the 2d cameras.
+///
+/// The 2d cameras don't have a depth texture so their OIT phase is drawn without one.
+pub struct OitNode<P: PhaseItem>(PhantomData<P>);
+
+impl<P: PhaseItem> Default for OitNode<P> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl OitNode<OitPhaseItem> {
     pub const NAME: &str = "oit_node";
 }
 
-impl ViewNode for OitNode {
+impl OitNode<OitPhaseItem2d> {
+    pub const NAME: &str = "oit_node_2d";
+}
+
+impl<P: PhaseItem> ViewNode for OitNode<P> {
     type ViewQuery = (
         &'static ExtractedCamera,
-        &'static RenderPhase<OitPhaseItem>,
+        &'static OitCamera,
+        &'static RenderPhase<P>,
         &'static ViewTarget,
-        &'static OitLayersBindGroup,
         &'static ViewUniformOffset,
-        &'static ViewDepthTexture,
+        Option<&'static ViewDepthTexture>,
+        Option<&'static OitLayersBindGroup>,
+        Option<&'static OitRenderViewBindGroup>,
+        Option<&'static OitRenderPipelineId>,
+        Option<&'static OitResolveSlices>,
+        // The passes reading the layers before the resolve passes
+        (
+            Option<(
+                &'static OitDepthOutputTexture,
+                &'static OitDepthOutputPipelineId,
+            )>,
+            Option<(
+                &'static ViewPrepassTextures,
+                &'static OitMotionVectorsPipelineId,
+                &'static OitMotionVectorsViewBindGroup,
+                &'static PreviousViewProjectionUniformOffset,
+            )>,
+        ),
+        Option<&'static OitWeightedBlendedTextures>,
+        Option<&'static OitWeightedBlendedBindGroup>,
+        Option<&'static OitCompositePipelineId>,
+        Option<(
+            &'static OitDepthPeelingTextures,
+            &'static OitDepthPeelingBindGroups,
+            &'static OitDepthPeelingPipelineIds,
+        )>,
     );
 
+    // One arm per method, the passes shared by several methods are in the functions below
+    #[allow(clippy::too_many_lines)]
     fn run(
         &self,
         graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
-        (camera, render_phase, view_target, oit_layers_bind_group, view_uniform, depth): QueryItem<
-            Self::ViewQuery,
-        >,
+        (
+            camera,
+            oit_camera,
+            render_phase,
+            view_target,
+            view_uniform,
+            depth,
+            oit_layers_bind_group,
+            render_view_bind_group,
+            render_pipeline_id,
+            resolve_slices,
+            (depth_output, motion_vectors),
+            weighted_blended_textures,
+            weighted_blended_bind_group,
+            composite_pipeline_id,
+            depth_peeling,
+        ): QueryItem<Self::ViewQuery>,
         world: &World,
     ) -> Result<(), NodeRunError> {
         if render_phase.items.is_empty() {
             return Ok(());
         }
 
-        // draw oit phase
-        {
-            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
-                label: Some("oit_draw_pass"),
-                color_attachments: &[Some(view_target.get_color_attachment(Operations {
-                    load: LoadOp::Load,
-                    store: true,
-                }))],
-                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
-                    view: &depth.view,
-                    depth_ops: Some(Operations {
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        match oit_camera.method {
+            OitMethod::ABuffer
+            | OitMethod::MultiLayerAlphaBlending
+            | OitMethod::LinkedList { .. } => {
+                let (
+                    Some(oit_layers_bind_group),
+                    Some(render_view_bind_group),
+                    Some(pipeline_id),
+                    Some(resolve_slices),
+                    Some(depth_slice_bind_group),
+                ) = (
+                    oit_layers_bind_group,
+                    render_view_bind_group,
+                    render_pipeline_id,
+                    resolve_slices,
+                    world.get_resource::<OitDepthSliceBindGroup>(),
+                )
+                else {
+                    return Ok(());
+                };
+
+                // The resolve pass clears the layers so nothing is stored in them until its
+                // pipeline is ready, the transparent 3d items taken from bevy's phase are still
+                // drawn
+                let Some(pipeline) = pipeline_cache.get_render_pipeline(pipeline_id.0) else {
+                    if let Some(depth) = depth {
+                        for slice in &resolve_slices.slices {
+                            if slice.items.is_empty() {
+                                continue;
+                            }
+                            draw_transparent_3d(
+                                graph,
+                                render_context,
+                                world,
+                                camera,
+                                &resolve_slices.transparent_phase,
+                                slice.items.clone(),
+                                view_target,
+                                depth,
+                            );
+                        }
+                    }
+                    return Ok(());
+                };
+
+                // draw oit phase
+                draw_phase(
+                    graph,
+                    render_context,
+                    world,
+                    camera,
+                    render_phase,
+                    depth,
+                    &[Some(view_target.get_color_attachment(Operations {
                         load: LoadOp::Load,
-                        store: false,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
+                        store: true,
+                    }))],
+                );
+
+                copy_node_counter(render_context, world, graph.view_entity());
+
+                // the resolve passes clear the layers so these need to read them first
+                if let Some((texture, pipeline_id)) = depth_output {
+                    read_layers(
+                        render_context,
+                        "oit_depth_output_pass",
+                        RenderPassColorAttachment {
+                            view: &texture.0.default_view,
+                            resolve_target: None,
+                            ops: Operations {
+                                load: LoadOp::Clear(Color::NONE.into()),
+                                store: true,
+                            },
+                        },
+                        pipeline_cache.get_render_pipeline(pipeline_id.0),
+                        (render_view_bind_group, &[view_uniform.offset]),
+                        oit_layers_bind_group,
+                    );
+                }
+                if let Some((
+                    prepass_textures,
+                    pipeline_id,
+                    view_bind_group,
+                    previous_view_proj_offset,
+                )) = motion_vectors
+                {
+                    if let Some(texture) = &prepass_textures.motion_vectors {
+                        // Only the pixels with transparent fragments are replaced
+                        read_layers(
+                            render_context,
+                            "oit_motion_vectors_pass",
+                            RenderPassColorAttachment {
+                                view: &texture.default_view,
+                                resolve_target: None,
+                                ops: Operations {
+                                    load: LoadOp::Load,
+                                    store: true,
+                                },
+                            },
+                            pipeline_cache.get_render_pipeline(pipeline_id.0),
+                            (
+                                view_bind_group,
+                                &[view_uniform.offset, previous_view_proj_offset.offset],
+                            ),
+                            oit_layers_bind_group,
+                        );
+                    }
+                }
 
-            if let Some(viewport) = camera.viewport.as_ref() {
-                render_pass.set_camera_viewport(viewport);
+                // render oit, from back to front with the transparent 3d items in between
+                for slice in &resolve_slices.slices {
+                    // The pass ends before the transparent 3d items are drawn
+                    {
+                        let mut render_pass =
+                            render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                                label: Some("oit_render_pass"),
+                                color_attachments: &[Some(view_target.get_color_attachment(
+                                    Operations {
+                                        load: LoadOp::Load,
+                                        store: true,
+                                    },
+                                ))],
+                                depth_stencil_attachment: None,
+                            });
+
+                        render_pass.set_render_pipeline(pipeline);
+                        render_pass.set_bind_group(
+                            0,
+                            render_view_bind_group,
+                            &[view_uniform.offset],
+                        );
+                        // The draw uniforms in the layers bind group aren't read here
+                        render_pass.set_bind_group(1, oit_layers_bind_group, &[0]);
+                        render_pass.set_bind_group(2, depth_slice_bind_group, &[slice.offset]);
+                        render_pass.draw(0..3, 0..1);
+                    }
+
+                    // Only the 3d views have transparent 3d items
+                    if let Some(depth) = depth.filter(|_| !slice.items.is_empty()) {
+                        draw_transparent_3d(
+                            graph,
+                            render_context,
+                            world,
+                            camera,
+                            &resolve_slices.transparent_phase,
+                            slice.items.clone(),
+                            view_target,
+                            depth,
+                        );
+                    }
+                }
+
+                // The resolve pass runs per sample so it can't clear the counters itself
+                let msaa_samples = world.resource::<Msaa>().samples();
+                if oit_camera
+                    .method
+                    .stores_coverage(oit_camera.k_buffer, msaa_samples)
+                {
+                    clear_layer_ids(render_context, world, graph.view_entity());
+                }
             }
+            OitMethod::WeightedBlended => {
+                let (Some(textures), Some(bind_group), Some(pipeline_id)) = (
+                    weighted_blended_textures,
+                    weighted_blended_bind_group,
+                    composite_pipeline_id,
+                ) else {
+                    return Ok(());
+                };
+
+                // accumulate the oit phase
+                draw_phase(
+                    graph,
+                    render_context,
+                    world,
+                    camera,
+                    render_phase,
+                    depth,
+                    &[
+                        Some(RenderPassColorAttachment {
+                            view: &textures.accumulation.default_view,
+                            resolve_target: None,
+                            ops: Operations {
+                                load: LoadOp::Clear(Color::NONE.into()),
+                                store: true,
+                            },
+                        }),
+                        Some(RenderPassColorAttachment {
+                            view: &textures.revealage.default_view,
+                            resolve_target: None,
+                            ops: Operations {
+                                load: LoadOp::Clear(Color::WHITE.into()),
+                                store: true,
+                            },
+                        }),
+                    ],
+                );
+
+                // composite on the view target
+                let Some(pipeline) = pipeline_cache.get_render_pipeline(pipeline_id.0) else {
+                    return Ok(());
+                };
 
-            render_phase.render(&mut render_pass, world, graph.view_entity());
+                composite(
+                    render_context,
+                    "oit_composite_pass",
+                    camera,
+                    view_target,
+                    pipeline,
+                    bind_group,
+                );
+            }
+            OitMethod::DepthPeeling { peels } => {
+                let Some(depth_peeling) = depth_peeling else {
+                    return Ok(());
+                };
+                render_depth_peeling(
+                    graph,
+                    render_context,
+                    world,
+                    camera,
+                    render_phase,
+                    view_target,
+                    peels,
+                    depth_peeling,
+                );
+            }
+            // The meshes are queued in bevy's transparent phase instead
+            OitMethod::TransparentPhase => {}
         }
 
-        // render oit
-        {
-            let pipeline_id = world.resource::<OitRenderPipelineId>();
-            let pipeline_cache = world.resource::<PipelineCache>();
-            let render_view_bind_group = world.resource::<OitRenderViewBindGroup>();
-            let Some(pipeline) = pipeline_cache.get_render_pipeline(pipeline_id.0) else {
-                return Ok(());
-            };
-
-            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
-                label: Some("oit_render_pass"),
-                color_attachments: &[Some(view_target.get_color_attachment(Operations {
+        Ok(())
+    }
+}
+
+/// Renders the oit phase in the given color attachments using the depth of the view, if it has
+/// one
+fn draw_phase<P: PhaseItem>(
+    graph: &RenderGraphContext,
+    render_context: &mut RenderContext,
+    world: &World,
+    camera: &ExtractedCamera,
+    render_phase: &RenderPhase<P>,
+    depth: Option<&ViewDepthTexture>,
+    color_attachments: &[Option<RenderPassColorAttachment>],
+) {
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("oit_draw_pass"),
+        color_attachments,
+        depth_stencil_attachment: depth.map(|depth| RenderPassDepthStencilAttachment {
+            view: &depth.view,
+            // The transparent 3d items drawn between the resolve passes test against it again
+            depth_ops: Some(Operations {
+                load: LoadOp::Load,
+                store: true,
+            }),
+            stencil_ops: None,
+        }),
+    });
+
+    if let Some(viewport) = camera.viewport.as_ref() {
+        render_pass.set_camera_viewport(viewport);
+    }
+
+    render_phase.render(&mut render_pass, world, graph.view_entity());
+}
+
+/// Renders a range of the [`Transparent3d`] items taken from bevy's phase like bevy's main
+/// transparent pass
+#[allow(clippy::too_many_arguments)]
+fn draw_transparent_3d(
+    graph: &RenderGraphContext,
+    render_context: &mut RenderContext,
+    world: &World,
+    camera: &ExtractedCamera,
+    transparent_phase: &RenderPhase<Transparent3d>,
+    items: Range<usize>,
+    view_target: &ViewTarget,
+    depth: &ViewDepthTexture,
+) {
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("oit_transparent_3d_pass"),
+        color_attachments: &[Some(view_target.get_color_attachment(Operations {
+            load: LoadOp::Load,
+            store: true,
+        }))],
+        depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+            view: &depth.view,
+            depth_ops: Some(Operations {
+                load: LoadOp::Load,
+                store: true,
+            }),
+            stencil_ops: None,
+        }),
+    });
+
+    if let Some(viewport) = camera.viewport.as_ref() {
+        render_pass.set_camera_viewport(viewport);
+    }
+
+    transparent_phase.render_range(&mut render_pass, world, graph.view_entity(), items);
+}
+
+/// Renders the peels of [`OitMethod::DepthPeeling`] from front to back, blends them together in
+/// the accumulation texture and composites the result on the view target
+#[allow(clippy::too_many_arguments)]
+fn render_depth_peeling<P: PhaseItem>(
+    graph: &RenderGraphContext,
+    render_context: &mut RenderContext,
+    world: &World,
+    camera: &ExtractedCamera,
+    render_phase: &RenderPhase<P>,
+    view_target: &ViewTarget,
+    peels: usize,
+    (textures, bind_groups, pipeline_ids): (
+        &OitDepthPeelingTextures,
+        &OitDepthPeelingBindGroups,
+        &OitDepthPeelingPipelineIds,
+    ),
+) {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let (Some(peel_pipeline), Some(resolve_pipeline)) = (
+        pipeline_cache.get_render_pipeline(pipeline_ids.peel),
+        pipeline_cache.get_render_pipeline(pipeline_ids.resolve),
+    ) else {
+        return;
+    };
+
+    // The peels aren't multisampled so they read the first sample of the opaque depth from a copy
+    if let Some(opaque_depth) = &textures.opaque_depth {
+        let (Some(copy_depth_pipeline), Some(copy_depth_bind_group)) = (
+            pipeline_ids
+                .copy_depth
+                .and_then(|id| pipeline_cache.get_render_pipeline(id)),
+            &bind_groups.copy_depth,
+        ) else {
+            return;
+        };
+
+        let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("oit_depth_peeling_copy_depth_pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &opaque_depth.default_view,
+                depth_ops: Some(Operations {
+                    load: LoadOp::Clear(0.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_render_pipeline(copy_depth_pipeline);
+        render_pass.set_bind_group(0, copy_depth_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    // The first peel is tested against a cleared previous depth so it gets the
+    // nearest fragments
+    render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("oit_depth_peeling_clear_pass"),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view: &textures.accumulation.default_view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(Color::NONE.into()),
+                store: true,
+            },
+        })],
+        depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+            view: &textures.depth[1].default_view,
+            depth_ops: Some(Operations {
+                load: LoadOp::Clear(1.0),
+                store: true,
+            }),
+            stencil_ops: None,
+        }),
+    });
+
+    for peel in 0..peels {
+        draw_peel(
+            graph,
+            render_context,
+            world,
+            camera,
+            render_phase,
+            textures,
+            &bind_groups.peels[peel % 2],
+            peel % 2,
+        );
+
+        // blend the peel behind the previous ones
+        let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("oit_depth_peeling_peel_pass"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: &textures.accumulation.default_view,
+                resolve_target: None,
+                ops: Operations {
                     load: LoadOp::Load,
                     store: true,
-                }))],
-                depth_stencil_attachment: None,
-            });
-
-            render_pass.set_render_pipeline(pipeline);
-            render_pass.set_bind_group(0, render_view_bind_group, &[view_uniform.offset]);
-            render_pass.set_bind_group(1, oit_layers_bind_group, &[]);
-            render_pass.draw(0..3, 0..1);
-        }
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_render_pipeline(peel_pipeline);
+        render_pass.set_bind_group(0, &bind_groups.color, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
 
-        Ok(())
+    composite(
+        render_context,
+        "oit_depth_peeling_composite_pass",
+        camera,
+        view_target,
+        resolve_pipeline,
+        &bind_groups.accumulation,
+    );
+}
+
+/// Renders the nearest fragments behind the previous peel of [`OitMethod::DepthPeeling`]
+///
+/// The depth textures alternate between the peels, `depth_index` is the one written by this peel
+/// and the other one is read through `peel_bind_group`.
+#[allow(clippy::too_many_arguments)]
+fn draw_peel<P: PhaseItem>(
+    graph: &RenderGraphContext,
+    render_context: &mut RenderContext,
+    world: &World,
+    camera: &ExtractedCamera,
+    render_phase: &RenderPhase<P>,
+    textures: &OitDepthPeelingTextures,
+    peel_bind_group: &BindGroup,
+    depth_index: usize,
+) {
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some("oit_depth_peeling_draw_pass"),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view: &textures.color.default_view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(Color::NONE.into()),
+                store: true,
+            },
+        })],
+        depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+            view: &textures.depth[depth_index].default_view,
+            depth_ops: Some(Operations {
+                load: LoadOp::Clear(0.0),
+                store: true,
+            }),
+            stencil_ops: None,
+        }),
+    });
+
+    if let Some(viewport) = camera.viewport.as_ref() {
+        render_pass.set_camera_viewport(viewport);
+    }
+
+    // The draw functions don't know about the peels so the textures are bound here
+    render_pass.set_bind_group(3, peel_bind_group, &[]);
+    render_phase.render(&mut render_pass, world, graph.view_entity());
+}
+
+/// Draws a fullscreen triangle in `color_attachment` reading the layers of the view
+///
+/// The pass still runs when the pipeline isn't ready so the attachment gets its load op.
+fn read_layers(
+    render_context: &mut RenderContext,
+    label: &'static str,
+    color_attachment: RenderPassColorAttachment,
+    pipeline: Option<&RenderPipeline>,
+    (view_bind_group, view_offsets): (&BindGroup, &[u32]),
+    oit_layers_bind_group: &BindGroup,
+) {
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[Some(color_attachment)],
+        depth_stencil_attachment: None,
+    });
+
+    if let Some(pipeline) = pipeline {
+        render_pass.set_render_pipeline(pipeline);
+        render_pass.set_bind_group(0, view_bind_group, view_offsets);
+        // The draw uniforms in the layers bind group aren't read here
+        render_pass.set_bind_group(1, oit_layers_bind_group, &[0]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+/// Draws a fullscreen triangle on the view target reading the textures of `bind_group`
+fn composite(
+    render_context: &mut RenderContext,
+    label: &'static str,
+    camera: &ExtractedCamera,
+    view_target: &ViewTarget,
+    pipeline: &RenderPipeline,
+    bind_group: &BindGroup,
+) {
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[Some(view_target.get_color_attachment(Operations {
+            load: LoadOp::Load,
+            store: true,
+        }))],
+        depth_stencil_attachment: None,
+    });
+
+    if let Some(viewport) = camera.viewport.as_ref() {
+        render_pass.set_camera_viewport(viewport);
+    }
+
+    render_pass.set_render_pipeline(pipeline);
+    render_pass.set_bind_group(0, bind_group, &[]);
+    render_pass.draw(0..3, 0..1);
+}
+
+/// Resets the layer counters, or the heads of the linked lists, of a view
+fn clear_layer_ids(render_context: &mut RenderContext, world: &World, view_entity: Entity) {
+    if let Some(buffer) = world.resource::<OitBuffers>().get(&view_entity) {
+        render_context
+            .command_encoder()
+            .clear_buffer(&buffer.layer_ids_buffer, 0, None);
+    }
+}
+
+/// Copies the node pool counter of a view using [`OitMethod::LinkedList`], or the contention flag
+/// of [`OitMethod::MultiLayerAlphaBlending`], to its readback buffer so it can be reported
+fn copy_node_counter(render_context: &mut RenderContext, world: &World, view_entity: Entity) {
+    let (Some(buffer), Some(readback)) = (
+        world.resource::<OitBuffers>().get(&view_entity),
+        world
+            .resource::<OitNodeCounterReadbacks>()
+            .get(&view_entity),
+    ) else {
+        return;
+    };
+
+    if readback.start_copy() {
+        render_context.command_encoder().copy_buffer_to_buffer(
+            &buffer.layers_buffer,
+            buffer.status_offset(),
+            &readback.buffer,
+            0,
+            std::mem::size_of::<u32>() as u64,
+        );
     }
 }
@@ -1,109 +1,269 @@
-#import bevy_pbr::mesh_functions as mesh_functions
-#import bevy_pbr::mesh_types Mesh
+#define_import_path bevy_oit::oit_draw
 
-#import bevy_oit::oit_draw_bindings view, material, mesh, layers, layer_ids, oit_layers
+// This module needs to be the first import of the shader.
+// naga_oil doesn't remap the type of atomic results when importing a function
+// so the types need to end up in the same order as in this module.
 
-struct Vertex {
-    @location(0) position: vec3<f32>,
-    @location(1) normal: vec3<f32>,
-}
+#ifdef OIT_2D
+#import bevy_sprite::mesh2d_view_bindings view
+#else
+#import bevy_pbr::mesh_view_bindings view
+#endif
+#ifdef OIT_DEPTH_PEELING
+#import bevy_oit::oit_draw_bindings opaque_depth, previous_peel_depth
+#else ifdef OIT_TRANSPARENT_PHASE
+// Drawn in bevy's transparent phase, nothing is bound
+#else
+#import bevy_oit::oit_draw_bindings layers, layer_ids, oit_layers, oit_draw_uniform, OIT_LINKED_LIST_OVERFLOW, OIT_MLAB_CONTENDED
+#endif
+#import bevy_oit::oit_layer pack_layer, unpack_layer_color, unpack_layer_depth, layer_in_front, pack_k_buffer_layer, unpack_k_buffer_layer_color
+#ifdef OIT_COVERAGE
+#import bevy_oit::oit_layer pack_layer_coverage
+#endif
 
-struct VertexOutput {
-    @builtin(position) position: vec4<f32>,
-    @location(0) world_position: vec4<f32>,
-    @location(1) world_normal: vec3<f32>,
+struct OitFragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef OIT_WEIGHTED_BLENDED
+    @location(1) revealage: vec4<f32>,
+#endif
 }
 
-@vertex
-fn vertex(vertex: Vertex) -> VertexOutput {
-    var out: VertexOutput;
-    out.world_position = mesh_functions::mesh_position_local_to_world(mesh.model, vec4(vertex.position, 1.0));
-    out.position = mesh_functions::mesh_position_world_to_clip(out.world_position);
-    out.world_normal = mesh_normal_local_to_world(mesh, vertex.normal);
+// Stores the color in the OIT layers of the current pixel.
+// The returned value should be used as the output of the fragment shader.
+fn oit_draw(position: vec4f, color: vec4f, sample_mask: u32) -> OitFragmentOutput {
+    var out: OitFragmentOutput;
+
+#ifdef OIT_WEIGHTED_BLENDED
+    // Weighted blended OIT
+    // see: https://jcgt.org/published/0002/02/09/
+    //
+    // This is equation 10 of the paper using the reversed z of bevy,
+    // so closer fragments get a bigger weight
+    let weight = color.a * max(1e-2, 3e3 * pow(position.z, 3.0));
+    out.color = vec4(color.rgb * color.a, color.a) * weight;
+    out.revealage = vec4(color.a);
     return out;
-}
+#else ifdef OIT_DEPTH_PEELING
+    // Only keeps the nearest fragment that is behind the previous peel and in front of the
+    // opaque meshes, the depth test of the peel does the rest.
+    // Fragments with exactly the same depth as the previous peel get peeled together.
+    let pixel = vec2<i32>(position.xy);
+    if position.z < textureLoad(opaque_depth, pixel, 0) {
+        discard;
+    }
+    if position.z >= textureLoad(previous_peel_depth, pixel, 0) {
+        discard;
+    }
+    out.color = color;
+    return out;
+#else ifdef OIT_TRANSPARENT_PHASE
+    // The meshes are sorted by bevy's transparent phase and blended directly
+    out.color = color;
+    return out;
+#else
+#ifndef OIT_COVERAGE
+    // sample_mask contains the samples of the pixel covered by the fragment.
+    // The layers can't store it so the fragment is only kept when it covers the last sample,
+    // this way the fragments of adjacent triangles aren't stored twice.
+    let msaa_mask = 1u << (#{MSAA}u - 1u);
+    if (sample_mask & msaa_mask) == 0u {
+        out.color = vec4(0.0);
+        return out;
+    }
+#endif
+
+    let screen_index = i32(floor(position.x) + floor(position.y) * view.viewport.z);
+    let depth = apply_depth_bias(position.z);
+    // The buffers can be smaller than the target while they are being resized.
+    // Those pixels are blended directly without sorting.
+    let buffer_size = i32(arrayLength(&layer_ids));
+    if screen_index >= buffer_size {
+        out.color = color;
+        return out;
+    }
+
+    // The counter keeps counting past the number of layers so the resolve pass knows how many
+    // fragments were drawn on the pixel
+#ifdef OIT_MLAB
+    // Multi-layer alpha blending
+    // see: https://software.intel.com/content/www/us/en/develop/articles/multi-layer-alpha-blending.html
+    //
+    // WGSL doesn't have raster order views so the pixel is locked while its layers are updated.
+    // The atomics of WGSL are relaxed so this doesn't guarantee the next fragment taking the lock
+    // sees every write to the layers, the result is best effort.
+    // The spinning is bounded in case the GPU doesn't guarantee forward progress, the fragments
+    // that couldn't take the lock are blended directly.
+    for (var i = 0; i < MLAB_MAX_SPINS; i += 1) {
+        let previous = atomicOr(&layer_ids[screen_index], MLAB_LOCK);
+        if (previous & MLAB_LOCK) == 0 {
+            let counter = previous & ~OIT_MLAB_CONTENDED;
+            mlab_insert(screen_index, buffer_size, min(counter, oit_layers), color, depth);
+            // Releases the lock and counts the fragment without clearing OIT_MLAB_CONTENDED,
+            // which can be set while the lock is held
+            atomicAdd(&layer_ids[screen_index], 1 - MLAB_LOCK);
+            out.color = vec4(0.0);
+            return out;
+        }
+    }
+    // Flags the pixel for the overflow debug view, and the frame with the word after the last
+    // layer so it can be read back and logged
+    atomicOr(&layer_ids[screen_index], OIT_MLAB_CONTENDED);
+    atomicStore(&layers[arrayLength(&layers) - 2u], 1u);
+    out.color = color;
+    return out;
+#else ifdef OIT_LINKED_LIST
+    let node_index = atomicAdd(&layers.counter, 1u);
+    if node_index >= arrayLength(&layers.nodes) {
+        atomicOr(&layer_ids[screen_index], OIT_LINKED_LIST_OVERFLOW);
+#ifdef TAIL_BLEND
+        out.color = color;
+#else
+        out.color = vec4(0.0);
+#endif
+        return out;
+    }
 
-// WARN This is a copy of mesh_functions::mesh_normal_local_to_world but it doesn't assume that mesh is present
-fn mesh_normal_local_to_world(mesh: Mesh, vertex_normal: vec3<f32>) -> vec3<f32> {
-    return normalize(
-        mat3x3(
-            mesh.inverse_transpose_model[0].xyz,
-            mesh.inverse_transpose_model[1].xyz,
-            mesh.inverse_transpose_model[2].xyz
-        ) * vertex_normal
-    );
+    layers.nodes[node_index].layer = pack_fragment(color, depth, sample_mask);
+    let head = atomicExchange(&layer_ids[screen_index], i32(node_index) + 1);
+    // A fragment that didn't fit in the pool could have flagged the pixel in the meantime
+    if (head & OIT_LINKED_LIST_OVERFLOW) != 0 {
+        atomicOr(&layer_ids[screen_index], OIT_LINKED_LIST_OVERFLOW);
+    }
+    layers.nodes[node_index].next = head & ~OIT_LINKED_LIST_OVERFLOW;
+    out.color = vec4(0.0);
+    return out;
+#else ifdef OIT_K_BUFFER
+    atomicAdd(&layer_ids[screen_index], 1);
+
+    // Keeps the layers sorted from front to back.
+    // The fragment takes the place of the first farther layer which then moves to the next one.
+    // Whatever is left at the end is the farthest fragment and doesn't fit in the layers.
+    var fragment = pack_k_buffer_layer(color, depth);
+    var farthest = color;
+    for (var i = 0; i < oit_layers; i += 1) {
+        let layer = atomicMax(&layers[screen_index + i * buffer_size], fragment);
+        if layer == 0u {
+            out.color = vec4(0.0);
+            return out;
+        }
+        if layer < fragment {
+            fragment = layer;
+            farthest = unpack_k_buffer_layer_color(layer);
+        }
+    }
+
+#ifdef TAIL_BLEND
+    out.color = farthest;
+#else
+    out.color = vec4(0.0);
+#endif
+    return out;
+#else
+    var layer_id = atomicAdd(&layer_ids[screen_index], 1);
+    if layer_id >= oit_layers {
+#ifdef TAIL_BLEND
+        out.color = color;
+#else
+        out.color = vec4(0.0);
+#endif
+        return out;
+    }
+
+    let layer_index = screen_index + layer_id * buffer_size;
+    layers[layer_index] = pack_fragment(color, depth, sample_mask);
+    out.color = vec4(0.0);
+    return out;
+#endif
+#endif
 }
 
-@fragment
-fn fragment(
-    @builtin(sample_mask) sample_mask: u32,
-    in: VertexOutput
-) -> @location(0) vec4<f32> {
-    // TODO this shading should be user customizable
-    let color = gooch_shading(
-        material.base_color,
-        in.world_normal,
-        view.world_position,
-    );
-
-    return oit_draw(in.position, color, sample_mask);
+// Only the methods storing the fragments in layers bind the draw uniform
+#ifndef OIT_WEIGHTED_BLENDED
+#ifndef OIT_DEPTH_PEELING
+#ifndef OIT_TRANSPARENT_PHASE
+// Moves the depth toward the camera by the OitDepthBias of the entity, in view space.
+// This assumes the depth only depends on the view space z, which is the case for the perspective
+// and orthographic projections of bevy.
+fn apply_depth_bias(depth: f32) -> f32 {
+    let bias = oit_draw_uniform.depth_bias;
+    if bias == 0.0 {
+        return depth;
+    }
+    let projection = view.projection;
+    // depth = (projection[2].z * z + projection[3].z) / (projection[2].w * z + projection[3].w)
+    let z = (projection[3].z - depth * projection[3].w) / (depth * projection[2].w - projection[2].z);
+    let biased_z = z + bias;
+    let biased_depth = (projection[2].z * biased_z + projection[3].z) / (projection[2].w * biased_z + projection[3].w);
+    // The layers are sorted by the bits of the depth which only works with positive floats
+    return max(biased_depth, 0.0);
 }
+#endif
+#endif
+#endif
 
-// Interpolates between a warm color and a cooler color based on the angle
-// between the normal and the light.
-fn gooch_shading(color: vec4<f32>, world_normal: vec3<f32>, camera_position: vec3<f32>) -> vec4<f32> {
-    let light_direction = normalize(vec3(-1.0, 2.0, 1.0));
-    let camera_direction = normalize(camera_position);
+// Packs the fragment with the samples it covers when the layers store them
+fn pack_fragment(color: vec4<f32>, depth: f32, sample_mask: u32) -> vec2<u32> {
+#ifdef OIT_COVERAGE
+    return pack_layer_coverage(pack_layer(color, depth), sample_mask);
+#else
+    return pack_layer(color, depth);
+#endif
+}
 
-    let warm = vec3(0.4, 0.4, 0.0);
-    let cool = vec3(0.0, 0.0, 0.4);
+#ifdef OIT_MLAB
+// Set on the counter of a pixel while a fragment updates its layers
+const MLAB_LOCK: i32 = 0x40000000;
+const MLAB_MAX_SPINS: i32 = 1024;
 
-    let a = 0.2;
-    let b = 0.8;
+fn mlab_load(index: i32) -> vec2<u32> {
+    return vec2(atomicLoad(&layers[index * 2]), atomicLoad(&layers[index * 2 + 1]));
+}
 
-    // diffuse
-    let gooch = dot(normalize(world_normal), light_direction) * 0.5 + 0.5;
-    var gooch_color = gooch  * (warm + b * color.rgb) +
-        (1.0 - gooch) * (cool + a * color.rgb);
+fn mlab_store(index: i32, layer: vec2<u32>) {
+    atomicStore(&layers[index * 2], layer.x);
+    atomicStore(&layers[index * 2 + 1], layer.y);
+}
 
-    // specular
-    let R = reflect(-light_direction, normalize(world_normal));
-    let ER = clamp(dot(camera_direction, normalize(R)), 0.0, 1.0);
-    let specular_strength = pow(ER, 2.0);
-    let spec = gooch_color * specular_strength;
+// Inserts the fragment in the layers sorted from front to back.
+// When all the layers are used the two farthest ones are merged so no fragment is dropped.
+// The layers store premultiplied colors.
+fn mlab_insert(screen_index: i32, buffer_size: i32, count: i32, color: vec4<f32>, depth: f32) {
+    let fragment = pack_layer(vec4(color.rgb * color.a, color.a), depth);
 
-    return vec4(gooch_color.rgb + spec, color.a);
-}
+    var position = count;
+    for (var i = 0; i < count; i += 1) {
+        if layer_in_front(fragment, mlab_load(screen_index + i * buffer_size)) {
+            position = i;
+            break;
+        }
+    }
 
-fn oit_draw(position: vec4f, color: vec4f, sample_mask: u32) -> vec4f {
-    // This feels super hacky
-    // sample_mask contains a bit for the current sample index
-    // so if MSAA == 8 then any bit between 0 and 8 bits might be enabled
-    //
-    // We only want to render 1 sample so we skip any samples that isn't the last one
-#ifdef MSAA
-    let msaa_mask = 1u << (#{MSAA}u - 1u);
-    if sample_mask < msaa_mask {
-        return vec4(0.0);
+    if count == oit_layers && position == count {
+        // The fragment is behind every layer, merge it with the last one
+        let last_index = screen_index + (count - 1) * buffer_size;
+        let last = mlab_load(last_index);
+        mlab_store(last_index, mlab_merge(last, fragment));
+        return;
     }
-#endif
 
-    let screen_index = i32(floor(position.x) + floor(position.y) * view.viewport.z);
-    let buffer_size = i32(view.viewport.z * view.viewport.w);
+    let evicted = mlab_load(screen_index + (oit_layers - 1) * buffer_size);
+    for (var i = min(count, oit_layers - 1); i > position; i -= 1) {
+        mlab_store(screen_index + i * buffer_size, mlab_load(screen_index + (i - 1) * buffer_size));
+    }
+    mlab_store(screen_index + position * buffer_size, fragment);
 
-    var layer_id = atomicAdd(&layer_ids[screen_index], 1);
-    if layer_id >= oit_layers {
-        atomicStore(&layer_ids[screen_index], oit_layers);
-#ifdef TAIL_BLEND
-        return color;
-#else
-        return vec4(0.0);
-#endif
+    if count == oit_layers {
+        // The last layer got pushed out by the fragment
+        let last_index = screen_index + (count - 1) * buffer_size;
+        mlab_store(last_index, mlab_merge(mlab_load(last_index), evicted));
     }
+}
 
-    let layer_index = screen_index + layer_id * buffer_size;
-    let packed_color = pack4x8unorm(color);
-    let depth = bitcast<u32>(position.z);
-    layers[layer_index] = vec2(packed_color, depth);
-    return vec4(0.0);
-}
\ No newline at end of file
+// Blends the back layer behind the front layer, the result keeps the depth of the front layer
+fn mlab_merge(front: vec2<u32>, back: vec2<u32>) -> vec2<u32> {
+    let front_color = unpack_layer_color(front);
+    let back_color = unpack_layer_color(back);
+    let color = front_color + (1.0 - front_color.a) * back_color;
+    return pack_layer(color, unpack_layer_depth(front));
+}
+#endif
@@ -1,24 +1,63 @@
 #define_import_path bevy_oit::oit_draw_bindings
 
-#import bevy_render::view View
-#import bevy_pbr::mesh_types Mesh
-
-@group(0) @binding(0)
-var<uniform> view: View;
+// Depth peeling doesn't need storage buffers, the fragments are tested against the depth of the
+// opaque meshes and the depth of the previous peel.
+// The peels aren't multisampled, with MSAA the first sample of the opaque depth is copied to a
+// single sampled texture before the first peel.
+#ifdef OIT_DEPTH_PEELING
+@group(3) @binding(0)
+var opaque_depth: texture_depth_2d;
 
-struct OitMaterial {
-    base_color: vec4<f32>,
-};
-@group(1) @binding(0)
-var<uniform> material: OitMaterial;
+@group(3) @binding(1)
+var previous_peel_depth: texture_depth_2d;
+#else
+// The k-buffer stores each layer in a single u32 so it can be updated atomically
+#ifdef OIT_K_BUFFER
+@group(3) @binding(0)
+var<storage, read_write> layers: array<atomic<u32>>;
+// MLAB uses atomics to access the layers so the writes are visible to the next fragment that
+// takes the lock of the pixel. Each layer is still a vec2<u32>.
+#else ifdef OIT_MLAB
+@group(3) @binding(0)
+var<storage, read_write> layers: array<atomic<u32>>;
+// The linked lists use the layers buffer as a pool of nodes shared by the whole target.
+// layer_ids stores the index of the first node of each pixel plus one, 0 is the end of a list.
+#else ifdef OIT_LINKED_LIST
+struct OitNode {
+    layer: vec2<u32>,
+    next: i32,
+}
 
-@group(2) @binding(0)
-var<uniform> mesh: Mesh;
+struct OitNodePool {
+    // Keeps counting past the size of the pool so the number of nodes needed can be read back
+    counter: atomic<u32>,
+    nodes: array<OitNode>,
+}
 
+@group(3) @binding(0)
+var<storage, read_write> layers: OitNodePool;
+#else
 @group(3) @binding(0)
 var<storage, read_write> layers: array<vec2<u32>>;
+#endif
 
 @group(3) @binding(1)
 var<storage, read_write> layer_ids: array<atomic<i32>>;
 
-const oit_layers: i32 = #{OIT_LAYERS};
\ No newline at end of file
+// The settings of the entity being drawn
+struct OitDrawUniform {
+    // See OitDepthBias
+    depth_bias: f32,
+}
+
+@group(3) @binding(2)
+var<uniform> oit_draw_uniform: OitDrawUniform;
+#endif
+
+const oit_layers: i32 = #{OIT_LAYERS};
+
+// Set on the head of the linked list of the pixels that lost fragments because the pool was full
+const OIT_LINKED_LIST_OVERFLOW: i32 = 0x40000000;
+// Set on the counter of the pixels that had fragments blended without sorting because they
+// couldn't take the MLAB lock
+const OIT_MLAB_CONTENDED: i32 = 0x20000000;
\ No newline at end of file
@@ -0,0 +1,123 @@
+#define_import_path bevy_oit::oit_layer
+
+// Packs a fragment in the format stored in the OIT layers.
+//
+// Without OIT_HDR the color is stored with pack4x8unorm and the depth is stored as is.
+//
+// With OIT_HDR the rgb channels are stored as rgb9e5 so they can go above 1.0.
+// The alpha is stored in the 8 least significant bits of the depth.
+// Positive floats keep the same order as their bits so the layers can still be sorted by depth.
+fn pack_layer(color: vec4<f32>, depth: f32) -> vec2<u32> {
+#ifdef OIT_HDR
+    let alpha = u32(round(saturate(color.a) * 255.0));
+    return vec2(pack_rgb9e5(color.rgb), (bitcast<u32>(depth) & 0xFFFFFF00u) | alpha);
+#else
+    return vec2(pack4x8unorm(color), bitcast<u32>(depth));
+#endif
+}
+
+fn unpack_layer_color(layer: vec2<u32>) -> vec4<f32> {
+#ifdef OIT_HDR
+    return vec4(unpack_rgb9e5(layer.x), f32(layer.y & 0xFFu) / 255.0);
+#else
+    return unpack4x8unorm(layer.x);
+#endif
+}
+
+fn unpack_layer_depth(layer: vec2<u32>) -> f32 {
+    return bitcast<f32>(layer.y);
+}
+
+// The depth of a layer without the alpha of OIT_HDR and the coverage of OIT_COVERAGE packed in
+// its least significant bits, for the passes that write or reproject the depth.
+// unpack_layer_depth() is enough to sort the layers.
+fn unpack_layer_masked_depth(layer: vec2<u32>) -> f32 {
+    var low_bits = 0u;
+#ifdef OIT_HDR
+    low_bits = 0xFFu;
+#endif
+#ifdef OIT_COVERAGE
+    low_bits |= oit_coverage_mask();
+#endif
+    return bitcast<f32>(layer.y & ~low_bits);
+}
+
+// Whether layer a is in front of layer b.
+// Layers with the same depth are ordered by the rest of their bits, the fragments of a pixel are
+// stored in a nondeterministic order so the order they were drawn in can't be used.
+fn layer_in_front(a: vec2<u32>, b: vec2<u32>) -> bool {
+    return a.y > b.y || (a.y == b.y && a.x > b.x);
+}
+
+#ifdef OIT_COVERAGE
+// With MSAA the coverage mask of the fragment is stored in the least significant bits of the
+// depth, above the alpha with OIT_HDR, so the resolve pass can blend it per sample.
+// OIT_COVERAGE is the number of samples.
+fn oit_coverage_shift() -> u32 {
+#ifdef OIT_HDR
+    return 8u;
+#else
+    return 0u;
+#endif
+}
+
+fn oit_coverage_mask() -> u32 {
+    return ((1u << #{OIT_COVERAGE}u) - 1u) << oit_coverage_shift();
+}
+
+fn pack_layer_coverage(layer: vec2<u32>, coverage: u32) -> vec2<u32> {
+    let mask = oit_coverage_mask();
+    return vec2(layer.x, (layer.y & ~mask) | ((coverage << oit_coverage_shift()) & mask));
+}
+
+fn unpack_layer_coverage(layer: vec2<u32>) -> u32 {
+    return (layer.y & oit_coverage_mask()) >> oit_coverage_shift();
+}
+#endif
+
+// Packs a fragment in a single u32 for the k-buffer so it can be sorted with atomicMax().
+//
+// The depth is stored as a f16 in the 16 most significant bits so bigger values are closer.
+// The color is stored with 4 bits per channel in the 16 least significant bits.
+// 0 is reserved for empty layers.
+fn pack_k_buffer_layer(color: vec4<f32>, depth: f32) -> u32 {
+    let depth_bits = pack2x16float(vec2(depth, 0.0)) & 0xFFFFu;
+    let c = vec4<u32>(round(saturate(color) * 15.0));
+    return (depth_bits << 16u) | (c.r << 12u) | (c.g << 8u) | (c.b << 4u) | c.a;
+}
+
+fn unpack_k_buffer_layer_color(layer: u32) -> vec4<f32> {
+    let c = vec4((layer >> 12u) & 0xFu, (layer >> 8u) & 0xFu, (layer >> 4u) & 0xFu, layer & 0xFu);
+    return vec4<f32>(c) / 15.0;
+}
+
+fn unpack_k_buffer_layer_depth(layer: u32) -> f32 {
+    return unpack2x16float(layer >> 16u).x;
+}
+
+// Shared exponent packing, see the EXT_texture_shared_exponent spec
+// https://registry.khronos.org/OpenGL/extensions/EXT/EXT_texture_shared_exponent.txt
+const RGB9E5_EXPONENT_BIAS: f32 = 15.0;
+const RGB9E5_MANTISSA_BITS: f32 = 9.0;
+// (2^9 - 1) / 2^9 * 2^(31 - 15)
+const RGB9E5_MAX: f32 = 65408.0;
+
+fn pack_rgb9e5(color: vec3<f32>) -> u32 {
+    let rgb = clamp(color, vec3(0.0), vec3(RGB9E5_MAX));
+    let max_channel = max(rgb.r, max(rgb.g, rgb.b));
+
+    var exponent = max(-RGB9E5_EXPONENT_BIAS - 1.0, floor(log2(max_channel))) + 1.0 + RGB9E5_EXPONENT_BIAS;
+    let max_mantissa = floor(max_channel / exp2(exponent - RGB9E5_EXPONENT_BIAS - RGB9E5_MANTISSA_BITS) + 0.5);
+    if max_mantissa == exp2(RGB9E5_MANTISSA_BITS) {
+        exponent += 1.0;
+    }
+
+    let mantissa = vec3<u32>(floor(rgb / exp2(exponent - RGB9E5_EXPONENT_BIAS - RGB9E5_MANTISSA_BITS) + 0.5));
+    return mantissa.r | (mantissa.g << 9u) | (mantissa.b << 18u) | (u32(exponent) << 27u);
+}
+
+fn unpack_rgb9e5(packed: u32) -> vec3<f32> {
+    let exponent = f32(packed >> 27u);
+    let mantissa = vec3(packed & 0x1FFu, (packed >> 9u) & 0x1FFu, (packed >> 18u) & 0x1FFu);
+    return vec3<f32>(mantissa) * exp2(exponent - RGB9E5_EXPONENT_BIAS - RGB9E5_MANTISSA_BITS);
+}
@@ -1,76 +1,301 @@
 #import bevy_render::view  View
+#import bevy_oit::oit_layer unpack_layer_color, unpack_layer_depth, unpack_layer_masked_depth, layer_in_front, unpack_k_buffer_layer_color, unpack_k_buffer_layer_depth
+#ifdef OIT_COVERAGE
+#import bevy_oit::oit_layer unpack_layer_coverage
+#endif
 
 @group(0) @binding(0)
 var<uniform> view: View;
 
+#ifdef OIT_MOTION_VECTORS
+struct PreviousViewProjection {
+    view_proj: mat4x4<f32>,
+};
+@group(0) @binding(1)
+var<uniform> previous_view_proj: PreviousViewProjection;
+#endif
+
+#ifdef OIT_K_BUFFER
+@group(1) @binding(0)
+var<storage, read_write> layers: array<u32>;
+#else ifdef OIT_LINKED_LIST
+struct OitNode {
+    layer: vec2<u32>,
+    next: i32,
+}
+
+struct OitNodePool {
+    counter: atomic<u32>,
+    nodes: array<OitNode>,
+}
+
+@group(1) @binding(0)
+var<storage, read_write> layers: OitNodePool;
+#else
 @group(1) @binding(0)
 var<storage, read_write> layers: array<vec2<u32>>;
+#endif
 
 @group(1) @binding(1)
 var<storage, read_write> layer_ids: array<atomic<i32>>;
 
-var<private> fragment_list: array<vec2<u32>, 32>;
+// The resolve is split in several passes when items of the Transparent3d phase are drawn
+// between the fragments, each pass only blends the fragments in its depth range
+struct OitDepthSlice {
+    far: f32,
+    near: f32,
+    last: u32,
+};
+@group(2) @binding(0)
+var<uniform> depth_slice: OitDepthSlice;
 
 const oit_layers: i32 = #{OIT_LAYERS};
+const OIT_LINKED_LIST_OVERFLOW: i32 = 0x40000000;
+const OIT_MLAB_CONTENDED: i32 = 0x20000000;
+
+// The sorting network needs a power of two number of entries.
+// The unused entries stay at 0 so they end up behind every fragment.
+#ifdef OIT_SORT_NETWORK_SIZE
+var<private> fragment_list: array<vec2<u32>, #{OIT_SORT_NETWORK_SIZE}>;
+#else
+var<private> fragment_list: array<vec2<u32>, #{OIT_LAYERS}>;
+#endif
+
+// The number of fragments in fragment_list
+var<private> fragment_count: i32;
+
+#ifdef OIT_COVERAGE
+// The bit of the sample being resolved, the fragments that don't cover it are skipped
+var<private> sample_bit: u32;
+#endif
 
 struct FullscreenVertexOutput {
     @builtin(position) position: vec4<f32>,
     @location(0) uv: vec2<f32>,
 };
+// With OIT_COVERAGE using the sample index runs the resolve once per sample
 @fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
-    let buffer_size = i32(view.viewport.z * view.viewport.w);
+fn fragment(
+    in: FullscreenVertexOutput,
+#ifdef OIT_COVERAGE
+    @builtin(sample_index) sample_index: u32,
+#endif
+) -> @location(0) vec4<f32> {
+#ifdef OIT_COVERAGE
+    sample_bit = 1u << sample_index;
+#endif
+
+    let buffer_size = i32(arrayLength(&layer_ids));
     let screen_index = i32(floor(in.position.x) + floor(in.position.y)* view.viewport.z);
+    if screen_index >= buffer_size {
+        discard;
+    }
 
+#ifdef OIT_LINKED_LIST
+    // The linked list is sorted while it's read, only the nearest oit_layers fragments are kept
+    let head = atomicLoad(&layer_ids[screen_index]);
+    let pool_overflow = (head & OIT_LINKED_LIST_OVERFLOW) != 0;
+    let counter = gather_linked_list(head & ~OIT_LINKED_LIST_OVERFLOW);
+#else ifdef OIT_MLAB
+    let head = atomicLoad(&layer_ids[screen_index]);
+    let contended = (head & OIT_MLAB_CONTENDED) != 0;
+    let counter = head & ~OIT_MLAB_CONTENDED;
+#else
+    // The number of fragments drawn on this pixel, only the first oit_layers ones are stored
     let counter = atomicLoad(&layer_ids[screen_index]);
+#endif
     if counter == 0 {
-        clear(screen_index);
+        clear(screen_index, buffer_size);
         discard;
-    } else {
-        let final_color = sort(screen_index, buffer_size);
-        clear(screen_index);
-        return final_color;
     }
 
-    // show layer density
-    // clear(screen_index);
-    // if counter == 0 {
-    //     discard;
-    // } else {
-    //     let x = f32(counter) / f32(oit_layers);
-    //     return vec4(x, x, x, 0.1);
-    // }
+#ifdef OIT_DEBUG_LAYER_COUNT
+    clear(screen_index, buffer_size);
+    return vec4(heat_map(f32(min(counter, oit_layers)) / f32(oit_layers)), 1.0);
+#else ifdef OIT_DEBUG_OVERFLOW
+    clear(screen_index, buffer_size);
+#ifdef OIT_LINKED_LIST
+    if pool_overflow {
+        return vec4(1.0, 0.0, 1.0, 1.0);
+    }
+#else ifdef OIT_MLAB
+    if contended {
+        return vec4(1.0, 0.0, 1.0, 1.0);
+    }
+#endif
+    if counter <= oit_layers {
+        return vec4(0.0, 0.1, 0.0, 1.0);
+    }
+    // How many fragments were tail blended or dropped compared to the stored ones
+    return vec4(heat_map(f32(counter - oit_layers) / f32(oit_layers)), 1.0);
+#else ifdef OIT_DEBUG_DEPTH_COMPLEXITY
+    clear(screen_index, buffer_size);
+    return vec4(heat_map(f32(counter) / f32(2 * oit_layers)), 1.0);
+#else
+#ifdef OIT_K_BUFFER
+    let final_color = blend_k_buffer(screen_index, buffer_size);
+#else ifdef OIT_MLAB
+    let final_color = blend_mlab(screen_index, buffer_size);
+#else ifdef OIT_LINKED_LIST
+    let final_color = blend_fragment_list(fragment_count);
+#else
+    let final_color = sort(screen_index, buffer_size);
+#endif
+    clear(screen_index, buffer_size);
+    return final_color;
+#endif
 }
 
-fn clear(screen_index: i32) {
-    atomicStore(&layer_ids[screen_index], 0);
+// Maps a value between 0.0 and 1.0 to a blue, cyan, green, yellow, red color ramp
+fn heat_map(value: f32) -> vec3<f32> {
+    let t = saturate(value) * 4.0;
+    let r = saturate(t - 2.0);
+    let g = saturate(t) - saturate(t - 3.0);
+    let b = 1.0 - saturate(t - 1.0);
+    return vec3(r, g, b);
+}
+
+fn clear(screen_index: i32, buffer_size: i32) {
+    // The next passes still need the layers
+    if depth_slice.last == 0u {
+        return;
+    }
+#ifdef OIT_COVERAGE
+    // Every sample of the pixel reads the layers so they can't be cleared here,
+    // the node clears the counters once the resolve pass is done
+#else ifdef OIT_K_BUFFER
+    // The k-buffer relies on empty layers being 0
+    let counter = min(atomicLoad(&layer_ids[screen_index]), oit_layers);
+    for (var i = 0; i < counter; i += 1) {
+        layers[screen_index + buffer_size * i] = 0u;
+    }
+#else ifdef OIT_LINKED_LIST
+    // The nodes are allocated again every frame, only the head of the list needs to be reset
+#else
     layers[screen_index] = vec2(0u);
+#endif
+#ifndef OIT_COVERAGE
+    atomicStore(&layer_ids[screen_index], 0);
+#endif
 }
 
-fn sort(screen_index: i32, buffer_size: i32) -> vec4<f32> {
-    var counter = atomicLoad(&layer_ids[screen_index]);
+fn covers_sample(fragment: vec2<u32>) -> bool {
+#ifdef OIT_COVERAGE
+    return (unpack_layer_coverage(fragment) & sample_bit) != 0u;
+#else
+    return true;
+#endif
+}
+
+fn in_depth_slice(depth: f32) -> bool {
+#ifdef OIT_ALL_FRAGMENTS
+    // The passes running before the resolve passes read every fragment
+    return true;
+#else
+    return depth > depth_slice.far && depth <= depth_slice.near;
+#endif
+}
 
-    // fill list
-    for (var i = 0; i < counter; i += 1){
-       fragment_list[i] = layers[screen_index + buffer_size * i];
+// Whether the fragment is blended by this pass
+fn is_resolved(fragment: vec2<u32>) -> bool {
+    return covers_sample(fragment) && in_depth_slice(unpack_layer_depth(fragment));
+}
+
+#ifdef OIT_K_BUFFER
+// The k-buffer layers are already sorted from front to back
+fn blend_k_buffer(screen_index: i32, buffer_size: i32) -> vec4<f32> {
+    let counter = min(atomicLoad(&layer_ids[screen_index]), oit_layers);
+
+    var final_color = vec4(0.0);
+    for (var i = 0; i < counter; i += 1) {
+        let layer = layers[screen_index + buffer_size * i];
+        if !in_depth_slice(unpack_k_buffer_layer_depth(layer)) {
+            continue;
+        }
+        let color = unpack_k_buffer_layer_color(layer);
+        var base_color = vec4(color.rgb * color.a, color.a);
+        final_color = blend(final_color, base_color);
     }
 
-    // bubble sort
-    for (var i = counter; i >= 0; i -= 1) {
-        for (var j = 0; j < i; j += 1) {
-            if bitcast<f32>(fragment_list[j].y) < bitcast<f32>(fragment_list[j + 1].y) {
-                // swap
-                let temp = fragment_list[j + 1];
-                fragment_list[j + 1] = fragment_list[j];
-                fragment_list[j] = temp;
-            }
+    return final_color;
+}
+#endif
+
+#ifdef OIT_LINKED_LIST
+// Reads the linked list of a pixel and returns its length.
+// The nearest oit_layers fragments covering the sample are kept in fragment_list sorted from
+// front to back.
+fn gather_linked_list(head: i32) -> i32 {
+    let max_nodes = i32(arrayLength(&layers.nodes));
+    var counter = 0;
+    var node_index = head;
+    // The length check can only fail if the buffers are corrupted but it avoids infinite loops
+    while node_index > 0 && counter < max_nodes {
+        let node = layers.nodes[node_index - 1];
+        if is_resolved(node.layer) {
+            insert_fragment(node.layer, fragment_count);
+            fragment_count = min(fragment_count + 1, oit_layers);
+        }
+        counter += 1;
+        node_index = node.next;
+    }
+    return counter;
+}
+
+// Inserts the fragment in fragment_list, sorted from front to back
+fn insert_fragment(fragment: vec2<u32>, count: i32) {
+    var j = count - 1;
+    if count == oit_layers {
+        // Drop the farthest fragment
+        if layer_in_front(fragment_list[count - 1], fragment) {
+            return;
+        }
+        j -= 1;
+    }
+    while j >= 0 && layer_in_front(fragment, fragment_list[j]) {
+        fragment_list[j + 1] = fragment_list[j];
+        j -= 1;
+    }
+    fragment_list[j + 1] = fragment;
+}
+
+fn blend_fragment_list(counter: i32) -> vec4<f32> {
+    var final_color = vec4(0.0);
+    for (var i = 0; i < counter; i += 1) {
+        let color = unpack_layer_color(fragment_list[i]);
+        var base_color = vec4(color.rgb * color.a, color.a);
+        final_color = blend(final_color, base_color);
+    }
+    return final_color;
+}
+#endif
+
+#ifdef OIT_MLAB
+// The MLAB layers are already sorted from front to back and premultiplied
+fn blend_mlab(screen_index: i32, buffer_size: i32) -> vec4<f32> {
+    let counter = min(atomicLoad(&layer_ids[screen_index]) & ~OIT_MLAB_CONTENDED, oit_layers);
+
+    var final_color = vec4(0.0);
+    for (var i = 0; i < counter; i += 1) {
+        let layer = layers[screen_index + buffer_size * i];
+        if in_depth_slice(unpack_layer_depth(layer)) {
+            final_color = blend(final_color, unpack_layer_color(layer));
         }
     }
 
+    return final_color;
+}
+#endif
+
+#ifndef OIT_K_BUFFER
+#ifndef OIT_LINKED_LIST
+fn sort(screen_index: i32, buffer_size: i32) -> vec4<f32> {
+    fill_fragment_list(screen_index, buffer_size);
+
     // resolve blend
     var final_color = vec4(0.0);
-    for (var i = 0; i <= counter; i += 1) {
-        let color = unpack4x8unorm(fragment_list[i].r);
+    for (var i = 0; i < fragment_count; i += 1) {
+        let color = unpack_layer_color(fragment_list[i]);
         var base_color = vec4(color.rgb * color.a, color.a);
         final_color = blend(final_color, base_color);
     }
@@ -78,6 +303,176 @@ fn sort(screen_index: i32, buffer_size: i32) -> vec4<f32> {
     return final_color;
 }
 
+// Copies the fragments of the pixel in fragment_list sorted from front to back
+fn fill_fragment_list(screen_index: i32, buffer_size: i32) {
+    let counter = min(atomicLoad(&layer_ids[screen_index]), oit_layers);
+
+    for (var i = 0; i < counter; i += 1) {
+        let fragment = layers[screen_index + buffer_size * i];
+        if is_resolved(fragment) {
+            fragment_list[fragment_count] = fragment;
+            fragment_count += 1;
+        }
+    }
+
+    // closer fragments have a bigger depth
+#ifdef OIT_SORT_NETWORK_SIZE
+    bitonic_sort();
+#else
+    insertion_sort(fragment_count);
+#endif
+}
+#endif
+#endif
+
+#ifdef OIT_ALL_FRAGMENTS
+// Prepares the fragments of the pixel to be read from front to back with
+// front_to_back_fragment() and returns their number
+fn gather_front_to_back(screen_index: i32, buffer_size: i32) -> i32 {
+#ifdef OIT_LINKED_LIST
+    let head = atomicLoad(&layer_ids[screen_index]);
+    gather_linked_list(head & ~OIT_LINKED_LIST_OVERFLOW);
+    return fragment_count;
+#else ifdef OIT_K_BUFFER
+    return min(atomicLoad(&layer_ids[screen_index]), oit_layers);
+#else ifdef OIT_MLAB
+    return min(atomicLoad(&layer_ids[screen_index]) & ~OIT_MLAB_CONTENDED, oit_layers);
+#else
+    fill_fragment_list(screen_index, buffer_size);
+    return fragment_count;
+#endif
+}
+
+// Returns the depth and the alpha of the i-th fragment from the front.
+// The depth doesn't include the bits of the alpha and the coverage packed with it.
+fn front_to_back_fragment(screen_index: i32, buffer_size: i32, i: i32) -> vec2<f32> {
+#ifdef OIT_K_BUFFER
+    let layer = layers[screen_index + buffer_size * i];
+    return vec2(unpack_k_buffer_layer_depth(layer), unpack_k_buffer_layer_color(layer).a);
+#else ifdef OIT_MLAB
+    let layer = layers[screen_index + buffer_size * i];
+    return vec2(unpack_layer_masked_depth(layer), unpack_layer_color(layer).a);
+#else
+    let layer = fragment_list[i];
+    return vec2(unpack_layer_masked_depth(layer), unpack_layer_color(layer).a);
+#endif
+}
+#endif
+
+#ifdef OIT_DEPTH_OUTPUT
+// Writes the depth where the alpha of the fragments blended from front to back reaches
+// OIT_DEPTH_OUTPUT / 255, so 0 writes the depth of the nearest fragment.
+// This runs before the resolve passes so the layers aren't cleared yet.
+@fragment
+fn depth_output(in: FullscreenVertexOutput) -> @location(0) f32 {
+    let buffer_size = i32(arrayLength(&layer_ids));
+    let screen_index = i32(floor(in.position.x) + floor(in.position.y) * view.viewport.z);
+    if screen_index >= buffer_size {
+        discard;
+    }
+    let threshold = f32(#{OIT_DEPTH_OUTPUT}u) / 255.0;
+
+    let counter = gather_front_to_back(screen_index, buffer_size);
+    var alpha = 0.0;
+    for (var i = 0; i < counter; i += 1) {
+        let fragment = front_to_back_fragment(screen_index, buffer_size, i);
+        alpha += (1.0 - alpha) * fragment.y;
+        if alpha >= threshold {
+            return fragment.x;
+        }
+    }
+
+    // The pixel stays at the cleared depth
+    discard;
+}
+#endif
+
+#ifdef OIT_MOTION_VECTORS
+// Replaces the motion vector of the opaque surface with the one of the transparent fragment
+// contributing the most to the color of the pixel, when it contributes more than the opaque
+// surface behind the fragments.
+// The layers don't store the previous position of the fragments so only the motion of the
+// camera is taken into account, the fragments are reprojected with their depth.
+// front_to_back_fragment() masks the alpha and coverage bits off the depth so they don't change
+// which fragment is picked or where it's reprojected.
+@fragment
+fn motion_vectors(in: FullscreenVertexOutput) -> @location(0) vec2<f32> {
+    let buffer_size = i32(arrayLength(&layer_ids));
+    let screen_index = i32(floor(in.position.x) + floor(in.position.y) * view.viewport.z);
+    if screen_index >= buffer_size {
+        discard;
+    }
+
+    let counter = gather_front_to_back(screen_index, buffer_size);
+    var alpha = 0.0;
+    var dominant_contribution = 0.0;
+    var dominant_depth = 0.0;
+    for (var i = 0; i < counter; i += 1) {
+        let fragment = front_to_back_fragment(screen_index, buffer_size, i);
+        let contribution = (1.0 - alpha) * fragment.y;
+        if contribution > dominant_contribution {
+            dominant_contribution = contribution;
+            dominant_depth = fragment.x;
+        }
+        alpha += contribution;
+    }
+    if dominant_contribution <= 1.0 - alpha {
+        discard;
+    }
+
+    let uv = (in.position.xy - view.viewport.xy) / view.viewport.zw;
+    let ndc = vec4(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, dominant_depth, 1.0);
+    let world_position_t = view.inverse_view_proj * ndc;
+    let world_position = world_position_t / world_position_t.w;
+
+    // Same as the motion vectors of bevy's prepass
+    let clip_position_t = view.unjittered_view_proj * world_position;
+    let clip_position = clip_position_t.xy / clip_position_t.w;
+    let previous_clip_position_t = previous_view_proj.view_proj * world_position;
+    let previous_clip_position = previous_clip_position_t.xy / previous_clip_position_t.w;
+    return (clip_position - previous_clip_position) * vec2(0.5, -0.5);
+}
+#endif
+
+fn insertion_sort(counter: i32) {
+    for (var i = 1; i < counter; i += 1) {
+        let fragment = fragment_list[i];
+        var j = i - 1;
+        while j >= 0 && layer_in_front(fragment, fragment_list[j]) {
+            fragment_list[j + 1] = fragment_list[j];
+            j -= 1;
+        }
+        fragment_list[j + 1] = fragment;
+    }
+}
+
+#ifdef OIT_SORT_NETWORK_SIZE
+// Sorts the whole list with a fixed sequence of compare and swaps
+// This scales better than the insertion sort for big lists
+// see: https://en.wikipedia.org/wiki/Bitonic_sorter
+fn bitonic_sort() {
+    let size = #{OIT_SORT_NETWORK_SIZE};
+    for (var k = 2; k <= size; k <<= 1u) {
+        for (var j = k >> 1u; j > 0; j >>= 1u) {
+            for (var i = 0; i < size; i += 1) {
+                let l = i ^ j;
+                if l <= i {
+                    continue;
+                }
+
+                let a = fragment_list[i];
+                let b = fragment_list[l];
+                let descending = (i & k) == 0;
+                if (descending && layer_in_front(b, a)) || (!descending && layer_in_front(a, b)) {
+                    fragment_list[i] = b;
+                    fragment_list[l] = a;
+                }
+            }
+        }
+    }
+}
+#endif
+
 // OVER operator using premultiplied alpha
 // see: https://en.wikipedia.org/wiki/Alpha_compositing
 fn blend(color_a: vec4<f32>, color_b: vec4<f32>) -> vec4<f32> {
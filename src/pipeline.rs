@@ -1,220 +1,921 @@
+use std::{hash::Hash, marker::PhantomData};
+
 use bevy::{
-    pbr::{MeshPipeline, MeshPipelineKey},
+    core_pipeline::{
+        prepass::{ViewPrepassTextures, MOTION_VECTOR_PREPASS_FORMAT},
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    pbr::{
+        EnvironmentMapLight, MeshPipeline, MeshPipelineKey, PreviousViewProjection,
+        PreviousViewProjectionUniforms, ScreenSpaceAmbientOcclusionSettings,
+    },
     prelude::*,
     render::{
-        mesh::MeshVertexBufferLayout,
+        mesh::{GpuMesh, MeshVertexBufferLayout},
+        render_asset::RenderAssets,
         render_resource::{
-            AsBindGroup, BindGroup, BindGroupLayout, BlendComponent, BlendState,
-            CachedRenderPipelineId, ColorTargetState, ColorWrites, CompareFunction, DepthBiasState,
-            DepthStencilState, MultisampleState, PipelineCache, RenderPipelineDescriptor,
-            ShaderDefVal, ShaderStages, ShaderType, SpecializedMeshPipeline,
-            SpecializedMeshPipelineError, StencilState, StorageBuffer, TextureFormat,
+            BindGroup, BindGroupLayout, BlendComponent, BlendState, Buffer, BufferDescriptor,
+            BufferUsages, CachedRenderPipelineId, ColorTargetState, ColorWrites, CompareFunction,
+            DepthBiasState, DepthStencilState, MultisampleState, PipelineCache,
+            RenderPipelineDescriptor, ShaderDefVal, ShaderRef, ShaderStages, ShaderType,
+            SpecializedMeshPipeline, SpecializedMeshPipelineError, SpecializedRenderPipeline,
+            SpecializedRenderPipelines, StencilState, TextureFormat,
         },
         renderer::RenderDevice,
         texture::BevyDefault,
-        view::{ViewUniform, ViewUniforms},
+        view::{ExtractedView, ViewTarget, ViewUniform, ViewUniforms},
     },
+    sprite::{Mesh2dPipeline, Mesh2dPipelineKey},
     utils::HashMap,
 };
 
 use crate::{
-    material::OitMaterial,
+    depth_bias::{OitDrawUniform, OitDrawUniforms},
+    depth_output::DEPTH_OUTPUT_TEXTURE_FORMAT,
+    depth_peeling::PEEL_COLOR_TEXTURE_FORMAT,
+    linked_list::{OIT_NODE_POOL_HEADER_SIZE, OIT_NODE_SIZE},
+    material::{OitMaterial, OitMaterialKey},
+    material_2d::{OitMaterial2d, OitMaterial2dKey},
+    transparent_3d::OitDepthSlice,
     utils::{
-        bind_group_layout_types::{storage_buffer, uniform_buffer},
+        bind_group_layout_types::{storage_buffer, texture_depth_2d, uniform_buffer},
         BindingResouceExt, RenderDeviceExt, RenderPipelineDescriptorBuilder,
     },
-    OitLayersBindGroup, OIT_DRAW_SHADER_HANDLE, OIT_LAYERS, OIT_RENDER_SHADER_HANDLE,
+    weighted_blended::weighted_blended_targets,
+    OitCamera, OitDebugView, OitLayersBindGroup, OitMethod, OitSupport,
+    COLOR_OIT_MATERIAL_SHADER_HANDLE, OIT_RENDER_SHADER_HANDLE,
+    STANDARD_OIT_MATERIAL_SHADER_HANDLE,
 };
 
-#[derive(Resource)]
-pub struct OitDrawPipeline {
-    pub(crate) mesh_pipeline: MeshPipeline,
-    pub(crate) oit_material_bind_group_layout: BindGroupLayout,
-    pub(crate) oit_layers_bind_group_layout: BindGroupLayout,
+/// The layouts of the per view resources bound by the OIT draw pass
+#[derive(Resource, Clone)]
+pub struct OitLayersBindGroupLayout {
+    /// The buffers used to store the OIT layers and the [`OitDrawUniform`] of the drawn entity,
+    /// `None` when the device doesn't support storage buffers in fragment shaders.
+    pub layers: Option<BindGroupLayout>,
+    /// The depth textures read by [`OitMethod::DepthPeeling`], they are never multisampled
+    pub depth_peeling: BindGroupLayout,
 }
 
-impl FromWorld for OitDrawPipeline {
+impl FromWorld for OitLayersBindGroupLayout {
     fn from_world(world: &mut World) -> Self {
+        world.init_resource::<OitSupport>();
+        let support = *world.resource::<OitSupport>();
         let render_device = world.resource::<RenderDevice>();
 
-        let oit_material_bind_group_layout = OitMaterial::bind_group_layout(render_device);
-
-        let oit_layers_bind_group_layout = render_device.create_bind_group_layout_ext(
-            "oit_layers_bind_group_layout",
+        let layers = support.storage_buffers.then(|| {
+            render_device.create_bind_group_layout_ext(
+                "oit_layers_bind_group_layout",
+                ShaderStages::FRAGMENT,
+                [
+                    storage_buffer(false, false, None),
+                    storage_buffer(false, false, None),
+                    uniform_buffer(true, Some(OitDrawUniform::min_size())),
+                ],
+            )
+        });
+        let depth_peeling = render_device.create_bind_group_layout_ext(
+            "oit_depth_peeling_bind_group_layout",
             ShaderStages::FRAGMENT,
-            [
-                storage_buffer(false, false, None),
-                storage_buffer(false, false, None),
-            ],
+            [texture_depth_2d(false), texture_depth_2d(false)],
         );
+        OitLayersBindGroupLayout {
+            layers,
+            depth_peeling,
+        }
+    }
+}
 
-        let mesh_pipeline = world.resource::<MeshPipeline>().clone();
+/// Render pipeline data for a given [`OitMaterial`].
+#[derive(Resource)]
+pub struct OitDrawPipeline<M: OitMaterial> {
+    pub mesh_pipeline: MeshPipeline,
+    pub oit_material_bind_group_layout: BindGroupLayout,
+    pub oit_layers_bind_group_layout: OitLayersBindGroupLayout,
+    pub vertex_shader: Option<Handle<Shader>>,
+    pub fragment_shader: Handle<Shader>,
+    marker: PhantomData<M>,
+}
+
+impl<M: OitMaterial> FromWorld for OitDrawPipeline<M> {
+    fn from_world(world: &mut World) -> Self {
+        // This can run before the OitPlugin finished if the material plugin was added first
+        world.init_resource::<OitLayersBindGroupLayout>();
+
+        let asset_server = world.resource::<AssetServer>();
+        let render_device = world.resource::<RenderDevice>();
 
         OitDrawPipeline {
-            mesh_pipeline,
-            oit_material_bind_group_layout,
-            oit_layers_bind_group_layout,
+            mesh_pipeline: world.resource::<MeshPipeline>().clone(),
+            oit_material_bind_group_layout: M::bind_group_layout(render_device),
+            oit_layers_bind_group_layout: world.resource::<OitLayersBindGroupLayout>().clone(),
+            vertex_shader: match M::vertex_shader() {
+                ShaderRef::Default => None,
+                ShaderRef::Handle(handle) => Some(handle),
+                ShaderRef::Path(path) => Some(asset_server.load(path)),
+            },
+            fragment_shader: match M::fragment_shader() {
+                ShaderRef::Default => STANDARD_OIT_MATERIAL_SHADER_HANDLE.typed(),
+                ShaderRef::Handle(handle) => handle,
+                ShaderRef::Path(path) => asset_server.load(path),
+            },
+            marker: PhantomData,
         }
     }
 }
 
+/// The key of the OIT draw pipelines, `K` is the mesh key of bevy's pipeline it specializes.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
-pub struct OitKey {
-    pub mesh_key: MeshPipelineKey,
+pub struct OitKey<K = MeshPipelineKey> {
+    pub mesh_key: K,
+    pub method: OitMethod,
+    pub layers: usize,
     pub tail_blend: bool,
+    pub k_buffer: bool,
 }
 
-impl SpecializedMeshPipeline for OitDrawPipeline {
-    type Key = OitKey;
+impl<M: OitMaterial> SpecializedMeshPipeline for OitDrawPipeline<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = OitMaterialKey<M>;
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut desc = self.mesh_pipeline.specialize(key.mesh_key, layout)?;
+        let mut desc = specialize_oit_draw_pipeline(
+            &self.mesh_pipeline,
+            &self.oit_material_bind_group_layout,
+            &self.oit_layers_bind_group_layout,
+            key.oit_key,
+            layout,
+        )?;
 
-        desc.label = Some("oit_draw_mesh_pipeline".into());
+        if let Some(vertex_shader) = &self.vertex_shader {
+            desc.vertex.shader = vertex_shader.clone();
+        }
+        if let Some(frag) = desc.fragment.as_mut() {
+            frag.shader = self.fragment_shader.clone();
+        }
 
-        let mut layout = match key.mesh_key.msaa_samples() {
-            1 => vec![self.mesh_pipeline.view_layout.clone()],
-            _ => vec![self.mesh_pipeline.view_layout_multisampled.clone()],
-        };
-        layout.push(self.oit_material_bind_group_layout.clone());
-        layout.push(self.mesh_pipeline.mesh_layouts.model_only.clone());
-        layout.push(self.oit_layers_bind_group_layout.clone());
+        M::specialize(self, &mut desc, layout, key)?;
+        Ok(desc)
+    }
+}
+
+/// Computes the [`MeshPipelineKey`] bits that depend on the view.
+///
+/// Lighting and tonemapping need to match the opaque and transparent phases so this mirrors
+/// bevy's view key.
+pub(crate) fn oit_view_key(
+    view: &ExtractedView,
+    msaa: Msaa,
+    images: &RenderAssets<Image>,
+    tonemapping: Option<&Tonemapping>,
+    dither: Option<&DebandDither>,
+    environment_map: Option<&EnvironmentMapLight>,
+    ssao: Option<&ScreenSpaceAmbientOcclusionSettings>,
+) -> MeshPipelineKey {
+    let mut view_key =
+        MeshPipelineKey::from_msaa_samples(msaa.samples()) | MeshPipelineKey::from_hdr(view.hdr);
+    if environment_map.is_some_and(|environment_map| environment_map.is_loaded(images)) {
+        view_key |= MeshPipelineKey::ENVIRONMENT_MAP;
+    }
+    if !view.hdr {
+        if let Some(tonemapping) = tonemapping {
+            view_key |= MeshPipelineKey::TONEMAP_IN_SHADER;
+            view_key |= match tonemapping {
+                Tonemapping::None => MeshPipelineKey::TONEMAP_METHOD_NONE,
+                Tonemapping::Reinhard => MeshPipelineKey::TONEMAP_METHOD_REINHARD,
+                Tonemapping::ReinhardLuminance => {
+                    MeshPipelineKey::TONEMAP_METHOD_REINHARD_LUMINANCE
+                }
+                Tonemapping::AcesFitted => MeshPipelineKey::TONEMAP_METHOD_ACES_FITTED,
+                Tonemapping::AgX => MeshPipelineKey::TONEMAP_METHOD_AGX,
+                Tonemapping::SomewhatBoringDisplayTransform => {
+                    MeshPipelineKey::TONEMAP_METHOD_SOMEWHAT_BORING_DISPLAY_TRANSFORM
+                }
+                Tonemapping::TonyMcMapface => MeshPipelineKey::TONEMAP_METHOD_TONY_MC_MAPFACE,
+                Tonemapping::BlenderFilmic => MeshPipelineKey::TONEMAP_METHOD_BLENDER_FILMIC,
+            };
+        }
+        if let Some(DebandDither::Enabled) = dither {
+            view_key |= MeshPipelineKey::DEBAND_DITHER;
+        }
+    }
+    if ssao.is_some() {
+        view_key |= MeshPipelineKey::SCREEN_SPACE_AMBIENT_OCCLUSION;
+    }
+    view_key
+}
 
-        let mut defs = vec![
-            ShaderDefVal::Int("OIT_LAYERS".to_string(), OIT_LAYERS as i32),
-            ShaderDefVal::UInt("MSAA".to_string(), key.mesh_key.msaa_samples()),
+/// Computes the [`MeshPipelineKey`] of a mesh drawn in a view with the given `view_key`.
+pub(crate) fn oit_mesh_key(mesh: &GpuMesh, view_key: MeshPipelineKey) -> MeshPipelineKey {
+    let mut mesh_key = MeshPipelineKey::from_primitive_topology(mesh.primitive_topology) | view_key;
+    if mesh.morph_targets.is_some() {
+        mesh_key |= MeshPipelineKey::MORPH_TARGETS;
+    }
+    mesh_key
+}
+
+/// Specializes the mesh pipeline to render in the OIT draw pass.
+///
+/// The shaders are left untouched, only the shader defs are added.
+pub(crate) fn specialize_oit_draw_pipeline(
+    mesh_pipeline: &MeshPipeline,
+    material_layout: &BindGroupLayout,
+    oit_layers_layout: &OitLayersBindGroupLayout,
+    key: OitKey,
+    layout: &MeshVertexBufferLayout,
+) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+    let mut desc = mesh_pipeline.specialize(key.mesh_key, layout)?;
+
+    desc.label = Some("oit_draw_mesh_pipeline".into());
+
+    let mut bind_group_layout = match key.mesh_key.msaa_samples() {
+        1 => vec![mesh_pipeline.view_layout.clone()],
+        _ => vec![mesh_pipeline.view_layout_multisampled.clone()],
+    };
+    bind_group_layout.push(material_layout.clone());
+    // The mesh pipeline picks the mesh layout based on the skinning and the morph targets
+    bind_group_layout.push(desc.layout[1].clone());
+    match key.method {
+        OitMethod::DepthPeeling { .. } => {
+            bind_group_layout.push(oit_layers_layout.depth_peeling.clone());
+        }
+        method if method.uses_layers() => {
+            if let Some(layers_layout) = &oit_layers_layout.layers {
+                bind_group_layout.push(layers_layout.clone());
+            }
+        }
+        _ => {}
+    }
+
+    desc.layout = bind_group_layout;
+    specialize_oit_method(
+        &mut desc,
+        key,
+        key.mesh_key.msaa_samples(),
+        key.mesh_key.contains(MeshPipelineKey::HDR),
+    );
+
+    // Each peel keeps the nearest fragment behind the previous peel, it's tested against the
+    // opaque depth in the shader since the peel depth texture isn't multisampled
+    let depth_peeling = matches!(key.method, OitMethod::DepthPeeling { .. });
+    desc.depth_stencil = Some(DepthStencilState {
+        format: TextureFormat::Depth32Float,
+        depth_write_enabled: depth_peeling,
+        depth_compare: if depth_peeling {
+            CompareFunction::Greater
+        } else {
+            CompareFunction::GreaterEqual
+        },
+        stencil: StencilState::default(),
+        bias: DepthBiasState::default(),
+    });
+    desc.multisample = MultisampleState {
+        count: if depth_peeling {
+            1
+        } else {
+            key.mesh_key.msaa_samples()
+        },
+        mask: !0,
+        // The alpha is blended in the resolve pass, the coverage of the fragments comes from
+        // the rasterizer
+        alpha_to_coverage_enabled: false,
+    };
+
+    Ok(desc)
+}
+
+/// Render pipeline data for a given [`OitMaterial2d`].
+#[derive(Resource)]
+pub struct OitDraw2dPipeline<M: OitMaterial2d> {
+    pub mesh2d_pipeline: Mesh2dPipeline,
+    pub oit_material_bind_group_layout: BindGroupLayout,
+    pub oit_layers_bind_group_layout: OitLayersBindGroupLayout,
+    pub vertex_shader: Option<Handle<Shader>>,
+    pub fragment_shader: Handle<Shader>,
+    marker: PhantomData<M>,
+}
+
+impl<M: OitMaterial2d> FromWorld for OitDraw2dPipeline<M> {
+    fn from_world(world: &mut World) -> Self {
+        // This can run before the OitPlugin finished if the material plugin was added first
+        world.init_resource::<OitLayersBindGroupLayout>();
+
+        let asset_server = world.resource::<AssetServer>();
+        let render_device = world.resource::<RenderDevice>();
+
+        OitDraw2dPipeline {
+            mesh2d_pipeline: world.resource::<Mesh2dPipeline>().clone(),
+            oit_material_bind_group_layout: M::bind_group_layout(render_device),
+            oit_layers_bind_group_layout: world.resource::<OitLayersBindGroupLayout>().clone(),
+            vertex_shader: match M::vertex_shader() {
+                ShaderRef::Default => None,
+                ShaderRef::Handle(handle) => Some(handle),
+                ShaderRef::Path(path) => Some(asset_server.load(path)),
+            },
+            fragment_shader: match M::fragment_shader() {
+                ShaderRef::Default => COLOR_OIT_MATERIAL_SHADER_HANDLE.typed(),
+                ShaderRef::Handle(handle) => handle,
+                ShaderRef::Path(path) => asset_server.load(path),
+            },
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M: OitMaterial2d> SpecializedMeshPipeline for OitDraw2dPipeline<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = OitMaterial2dKey<M>;
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut desc = self
+            .mesh2d_pipeline
+            .specialize(key.oit_key.mesh_key, layout)?;
+
+        desc.label = Some("oit_draw_mesh2d_pipeline".into());
+
+        let mut bind_group_layout = vec![
+            self.mesh2d_pipeline.view_layout.clone(),
+            self.oit_material_bind_group_layout.clone(),
+            self.mesh2d_pipeline.mesh_layout.clone(),
         ];
-        if key.tail_blend {
-            defs.push(ShaderDefVal::from("TAIL_BLEND".to_string()));
+        // The 2d cameras don't have a depth texture so they can't use depth peeling
+        if key.oit_key.method.uses_layers() {
+            if let Some(layers_layout) = &self.oit_layers_bind_group_layout.layers {
+                bind_group_layout.push(layers_layout.clone());
+            }
         }
 
-        desc.layout = layout;
-        desc.vertex.shader = OIT_DRAW_SHADER_HANDLE.typed();
-        desc.vertex.shader_defs.extend_from_slice(&defs);
+        desc.layout = bind_group_layout;
+        specialize_oit_method(
+            &mut desc,
+            key.oit_key,
+            key.oit_key.mesh_key.msaa_samples(),
+            key.oit_key.mesh_key.contains(Mesh2dPipelineKey::HDR),
+        );
+
+        if let Some(vertex_shader) = &self.vertex_shader {
+            desc.vertex.shader = vertex_shader.clone();
+        }
+        // The draw shader reads the view from the mesh2d bindings
+        desc.vertex.shader_defs.push("OIT_2D".into());
         if let Some(frag) = desc.fragment.as_mut() {
-            frag.shader = OIT_DRAW_SHADER_HANDLE.typed();
-            frag.shader_defs.extend_from_slice(&defs);
-            if let Some(target) = frag.targets[0].as_mut() {
-                target.blend = Some(BlendState::ALPHA_BLENDING);
+            frag.shader = self.fragment_shader.clone();
+            frag.shader_defs.push("OIT_2D".into());
+        }
+
+        M::specialize(self, &mut desc, layout, key)?;
+        Ok(desc)
+    }
+}
+
+/// Computes the [`Mesh2dPipelineKey`] bits that depend on the view.
+///
+/// This mirrors the view key of bevy's 2d materials so the tonemapping matches the
+/// [`Transparent2d`](bevy::core_pipeline::core_2d::Transparent2d) phase.
+pub(crate) fn oit_view_2d_key(
+    view: &ExtractedView,
+    msaa: Msaa,
+    tonemapping: Option<&Tonemapping>,
+    dither: Option<&DebandDither>,
+) -> Mesh2dPipelineKey {
+    let mut view_key = Mesh2dPipelineKey::from_msaa_samples(msaa.samples())
+        | Mesh2dPipelineKey::from_hdr(view.hdr);
+    if !view.hdr {
+        if let Some(tonemapping) = tonemapping {
+            view_key |= Mesh2dPipelineKey::TONEMAP_IN_SHADER;
+            view_key |= match tonemapping {
+                Tonemapping::None => Mesh2dPipelineKey::TONEMAP_METHOD_NONE,
+                Tonemapping::Reinhard => Mesh2dPipelineKey::TONEMAP_METHOD_REINHARD,
+                Tonemapping::ReinhardLuminance => {
+                    Mesh2dPipelineKey::TONEMAP_METHOD_REINHARD_LUMINANCE
+                }
+                Tonemapping::AcesFitted => Mesh2dPipelineKey::TONEMAP_METHOD_ACES_FITTED,
+                Tonemapping::AgX => Mesh2dPipelineKey::TONEMAP_METHOD_AGX,
+                Tonemapping::SomewhatBoringDisplayTransform => {
+                    Mesh2dPipelineKey::TONEMAP_METHOD_SOMEWHAT_BORING_DISPLAY_TRANSFORM
+                }
+                Tonemapping::TonyMcMapface => Mesh2dPipelineKey::TONEMAP_METHOD_TONY_MC_MAPFACE,
+                Tonemapping::BlenderFilmic => Mesh2dPipelineKey::TONEMAP_METHOD_BLENDER_FILMIC,
+            };
+        }
+        if let Some(DebandDither::Enabled) = dither {
+            view_key |= Mesh2dPipelineKey::DEBAND_DITHER;
+        }
+    }
+    view_key
+}
+
+/// Adds the shader defs of the OIT method to a draw pipeline and replaces its color targets.
+///
+/// This is shared by the 3d and 2d draw pipelines, the bind group layouts, the depth and the
+/// multisampling are left to them.
+fn specialize_oit_method<K>(
+    desc: &mut RenderPipelineDescriptor,
+    key: OitKey<K>,
+    msaa_samples: u32,
+    hdr: bool,
+) {
+    let mut defs = vec![
+        ShaderDefVal::Int("OIT_LAYERS".to_string(), key.layers as i32),
+        ShaderDefVal::UInt("MSAA".to_string(), msaa_samples),
+    ];
+    if key.tail_blend {
+        defs.push(ShaderDefVal::from("TAIL_BLEND".to_string()));
+    }
+    if key.method == OitMethod::WeightedBlended {
+        defs.push(ShaderDefVal::from("OIT_WEIGHTED_BLENDED".to_string()));
+    }
+    if key.method == OitMethod::ABuffer && key.k_buffer {
+        defs.push(ShaderDefVal::from("OIT_K_BUFFER".to_string()));
+    }
+    if key.method == OitMethod::MultiLayerAlphaBlending {
+        defs.push(ShaderDefVal::from("OIT_MLAB".to_string()));
+    }
+    if let OitMethod::LinkedList { .. } = key.method {
+        defs.push(ShaderDefVal::from("OIT_LINKED_LIST".to_string()));
+    }
+    if let OitMethod::DepthPeeling { .. } = key.method {
+        defs.push(ShaderDefVal::from("OIT_DEPTH_PEELING".to_string()));
+    }
+    if key.method == OitMethod::TransparentPhase {
+        defs.push(ShaderDefVal::from("OIT_TRANSPARENT_PHASE".to_string()));
+    }
+    if hdr {
+        defs.push(ShaderDefVal::from("OIT_HDR".to_string()));
+    }
+    if key.method.stores_coverage(key.k_buffer, msaa_samples) {
+        defs.push(ShaderDefVal::UInt("OIT_COVERAGE".to_string(), msaa_samples));
+    }
+
+    desc.vertex.shader_defs.extend_from_slice(&defs);
+    if let Some(frag) = desc.fragment.as_mut() {
+        frag.shader_defs.extend_from_slice(&defs);
+        match key.method {
+            OitMethod::ABuffer
+            | OitMethod::MultiLayerAlphaBlending
+            | OitMethod::LinkedList { .. }
+            | OitMethod::TransparentPhase => {
+                if let Some(target) = frag.targets[0].as_mut() {
+                    target.blend = Some(BlendState::ALPHA_BLENDING);
+                }
+            }
+            OitMethod::WeightedBlended => frag.targets = weighted_blended_targets(),
+            OitMethod::DepthPeeling { .. } => {
+                if let Some(target) = frag.targets[0].as_mut() {
+                    target.format = PEEL_COLOR_TEXTURE_FORMAT;
+                    target.blend = None;
+                }
             }
         }
-        desc.depth_stencil = Some(DepthStencilState {
-            format: TextureFormat::Depth32Float,
-            depth_write_enabled: false,
-            depth_compare: CompareFunction::GreaterEqual,
-            stencil: StencilState::default(),
-            bias: DepthBiasState::default(),
+    }
+}
+
+pub struct OitBuffer {
+    /// The number of pixels the buffers can hold
+    pub size: usize,
+    /// The number of layers allocated for each pixel
+    pub layers: usize,
+    /// The number of nodes allocated for [`OitMethod::LinkedList`], the layers buffer is used as
+    /// the node pool when this isn't 0.
+    pub nodes: usize,
+    pub layers_buffer: Buffer,
+    pub layer_ids_buffer: Buffer,
+    /// The last target size that didn't fit the buffers
+    pub pending_size: usize,
+    /// The number of frames `pending_size` stayed the same
+    pub pending_frames: u32,
+}
+
+impl OitBuffer {
+    /// Creates the buffers directly on the GPU.
+    ///
+    /// wgpu zero initializes new buffers so nothing needs to be uploaded.
+    pub fn new(render_device: &RenderDevice, size: usize, layers: usize, nodes: usize) -> Self {
+        let layers_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("oit_layers_buffer"),
+            size: Self::layers_byte_size(size, layers, nodes),
+            // The node pool counter is reset and read back with copies
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
         });
-        desc.multisample = MultisampleState {
-            count: key.mesh_key.msaa_samples(),
-            mask: !0,
-            // TODO investigate how to use this for OIT
-            alpha_to_coverage_enabled: false,
-        };
+        let layer_ids_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("oit_layer_ids_buffer"),
+            size: (size * std::mem::size_of::<i32>()) as u64,
+            // The counters are cleared with a copy when the resolve pass runs per sample
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        OitBuffer {
+            size,
+            layers,
+            nodes,
+            layers_buffer,
+            layer_ids_buffer,
+            pending_size: size,
+            pending_frames: 0,
+        }
+    }
 
-        Ok(desc)
+    /// The number of bytes needed by the buffers of a target with `size` pixels
+    pub fn required_byte_size(size: usize, layers: usize, nodes: usize) -> u64 {
+        let layer_ids_size = size * std::mem::size_of::<i32>();
+        Self::layers_byte_size(size, layers, nodes) + layer_ids_size as u64
+    }
+
+    /// Whether each buffer fits in the limits of the device, wgpu panics when creating or
+    /// binding a bigger one.
+    pub fn fits_limits(
+        render_device: &RenderDevice,
+        size: usize,
+        layers: usize,
+        nodes: usize,
+    ) -> bool {
+        let limits = render_device.limits();
+        let max_size =
+            u64::from(limits.max_storage_buffer_binding_size).min(limits.max_buffer_size);
+        let layer_ids_size = (size * std::mem::size_of::<i32>()) as u64;
+        Self::layers_byte_size(size, layers, nodes) <= max_size && layer_ids_size <= max_size
+    }
+
+    /// The number of bytes currently allocated by the buffers
+    pub fn byte_size(&self) -> u64 {
+        Self::required_byte_size(self.size, self.layers, self.nodes)
+    }
+
+    /// The offset of the `u32` read back to report the problems of the frame, the counter of the
+    /// node pool or the flag set after the last layer by the fragments that couldn't take the
+    /// lock of [`OitMethod::MultiLayerAlphaBlending`].
+    pub fn status_offset(&self) -> u64 {
+        if self.nodes > 0 {
+            0
+        } else {
+            (self.size * self.layers * std::mem::size_of::<UVec2>()) as u64
+        }
+    }
+
+    fn layers_byte_size(size: usize, layers: usize, nodes: usize) -> u64 {
+        if nodes > 0 {
+            (OIT_NODE_POOL_HEADER_SIZE + nodes * OIT_NODE_SIZE) as u64
+        } else {
+            // The last layer is followed by the status of the frame, see `status_offset()`
+            ((size * layers + 1) * std::mem::size_of::<UVec2>()) as u64
+        }
     }
 }
 
 #[derive(Resource, Default, Deref, DerefMut)]
-#[allow(clippy::type_complexity)]
-pub struct OitBuffers(
-    pub HashMap<Entity, (usize, StorageBuffer<Vec<UVec2>>, StorageBuffer<Vec<i32>>)>,
-);
+pub struct OitBuffers(pub HashMap<Entity, OitBuffer>);
 
-#[derive(Resource, Deref)]
+#[derive(Component, Deref)]
 pub struct OitRenderViewBindGroup(pub BindGroup);
 
+#[derive(Component, Deref)]
+pub struct OitMotionVectorsViewBindGroup(pub BindGroup);
+
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::type_complexity)]
 pub fn queue_bind_groups(
     mut commands: Commands,
-    pipeline: Res<OitDrawPipeline>,
-    render_pipeline: Res<OitRenderPipeline>,
+    layers_layout: Res<OitLayersBindGroupLayout>,
+    render_pipeline: Option<Res<OitRenderPipeline>>,
     render_device: Res<RenderDevice>,
     buffers: Res<OitBuffers>,
+    draw_uniforms: Res<OitDrawUniforms>,
     view_uniforms: Res<ViewUniforms>,
+    previous_view_proj_uniforms: Res<PreviousViewProjectionUniforms>,
+    views: Query<(Entity, Option<&ViewPrepassTextures>), (With<ExtractedView>, With<OitCamera>)>,
 ) {
-    for (entity, (_, layers, layer_ids)) in &buffers.0 {
-        let bg = render_device.create_bind_group_ext(
+    if view_uniforms.uniforms.buffer().is_none() || draw_uniforms.buffer().is_none() {
+        return;
+    }
+    let (Some(layers_layout), Some(render_pipeline)) = (&layers_layout.layers, render_pipeline)
+    else {
+        return;
+    };
+
+    for (entity, prepass_textures) in &views {
+        let Some(buffer) = buffers.get(&entity) else {
+            continue;
+        };
+
+        let layers_bind_group = render_device.create_bind_group_ext(
             "oit_layers_bind_group",
-            &pipeline.oit_layers_bind_group_layout,
-            [layers.bind(), layer_ids.bind()],
+            layers_layout,
+            [
+                buffer.layers_buffer.bind(),
+                buffer.layer_ids_buffer.bind(),
+                draw_uniforms.bind(),
+            ],
         );
-        commands.entity(*entity).insert(OitLayersBindGroup(bg));
-    }
+        let view_bind_group = render_device.create_bind_group_ext(
+            "oit_render_params_bind_group",
+            &render_pipeline.view_bind_group_layout,
+            [view_uniforms.uniforms.bind()],
+        );
+        commands.entity(entity).insert((
+            OitLayersBindGroup(layers_bind_group),
+            OitRenderViewBindGroup(view_bind_group),
+        ));
 
-    let bind_group = render_device.create_bind_group_ext(
-        "oit_render_params_bind_group",
-        &render_pipeline.view_bind_group_layout,
-        [view_uniforms.uniforms.bind()],
-    );
-    commands.insert_resource(OitRenderViewBindGroup(bind_group));
+        // The previous view projections are only written for the views with a motion vector
+        // prepass
+        if prepass_textures.is_some_and(|textures| textures.motion_vectors.is_some())
+            && previous_view_proj_uniforms.uniforms.buffer().is_some()
+        {
+            let motion_vectors_view_bind_group = render_device.create_bind_group_ext(
+                "oit_motion_vectors_view_bind_group",
+                &render_pipeline.motion_vectors_view_bind_group_layout,
+                [
+                    view_uniforms.uniforms.bind(),
+                    previous_view_proj_uniforms.uniforms.bind(),
+                ],
+            );
+            commands
+                .entity(entity)
+                .insert(OitMotionVectorsViewBindGroup(
+                    motion_vectors_view_bind_group,
+                ));
+        }
+    }
 }
 
 #[derive(Resource)]
+#[allow(clippy::struct_field_names)]
 pub struct OitRenderPipeline {
     view_bind_group_layout: BindGroupLayout,
+    /// Also binds the view projection of the previous frame
+    motion_vectors_view_bind_group_layout: BindGroupLayout,
+    oit_layers_bind_group_layout: BindGroupLayout,
+    pub(crate) depth_slice_bind_group_layout: BindGroupLayout,
 }
 
 impl FromWorld for OitRenderPipeline {
     fn from_world(world: &mut World) -> Self {
-        let view_bind_group_layout = world
-            .resource::<RenderDevice>()
-            .create_bind_group_layout_ext(
-                "oit_render_view_layout",
-                ShaderStages::FRAGMENT,
-                [uniform_buffer(true, Some(ViewUniform::min_size()))],
-            );
+        let render_device = world.resource::<RenderDevice>();
+        let view_bind_group_layout = render_device.create_bind_group_layout_ext(
+            "oit_render_view_layout",
+            ShaderStages::FRAGMENT,
+            [uniform_buffer(true, Some(ViewUniform::min_size()))],
+        );
+        let motion_vectors_view_bind_group_layout = render_device.create_bind_group_layout_ext(
+            "oit_motion_vectors_view_layout",
+            ShaderStages::FRAGMENT,
+            [
+                uniform_buffer(true, Some(ViewUniform::min_size())),
+                uniform_buffer(true, Some(PreviousViewProjection::min_size())),
+            ],
+        );
+        let depth_slice_bind_group_layout = render_device.create_bind_group_layout_ext(
+            "oit_depth_slice_bind_group_layout",
+            ShaderStages::FRAGMENT,
+            [uniform_buffer(true, Some(OitDepthSlice::min_size()))],
+        );
+        let oit_layers_bind_group_layout = world
+            .resource::<OitLayersBindGroupLayout>()
+            .layers
+            .clone()
+            .expect("The OIT resolve pass needs storage buffers in fragment shaders");
         OitRenderPipeline {
             view_bind_group_layout,
+            motion_vectors_view_bind_group_layout,
+            oit_layers_bind_group_layout,
+            depth_slice_bind_group_layout,
         }
     }
 }
 
-#[derive(Resource, Deref)]
+/// The resolve pass uses an insertion sort up to this number of layers and a sorting network
+/// above it.
+pub const INSERTION_SORT_MAX_LAYERS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OitRenderKey {
+    pub method: OitMethod,
+    pub layers: usize,
+    pub msaa_samples: u32,
+    pub hdr: bool,
+    pub k_buffer: bool,
+    pub debug_view: OitDebugView,
+    pub pass: OitRenderPass,
+}
+
+/// The fullscreen passes reading the OIT layers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OitRenderPass {
+    /// Blends the fragments on the view target and clears the layers
+    Resolve,
+    /// Writes the depth output, with the alpha threshold of [`crate::OitDepthOutput`]
+    DepthOutput(u32),
+    /// Writes the motion vectors of the transparent fragments in the prepass texture
+    MotionVectors,
+}
+
+impl OitRenderKey {
+    /// The shader defs shared by the passes
+    fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut defs = vec![ShaderDefVal::Int(
+            "OIT_LAYERS".to_string(),
+            self.layers as i32,
+        )];
+        // naga_oil's #ifdef only checks if the def exists, so boolean defs are only pushed when
+        // they are true
+        if self.hdr {
+            defs.push("OIT_HDR".into());
+        }
+        // The k-buffer and MLAB layers are already sorted by the draw pass
+        // and the linked lists are sorted while they are read
+        if self.method == OitMethod::MultiLayerAlphaBlending {
+            defs.push("OIT_MLAB".into());
+        } else if let OitMethod::LinkedList { .. } = self.method {
+            defs.push("OIT_LINKED_LIST".into());
+        } else if self.k_buffer {
+            defs.push("OIT_K_BUFFER".into());
+        } else if self.layers > INSERTION_SORT_MAX_LAYERS {
+            defs.push(ShaderDefVal::Int(
+                "OIT_SORT_NETWORK_SIZE".to_string(),
+                self.layers.next_power_of_two() as i32,
+            ));
+        }
+        match self.debug_view {
+            OitDebugView::None => {}
+            OitDebugView::LayerCount => defs.push("OIT_DEBUG_LAYER_COUNT".into()),
+            OitDebugView::Overflow => defs.push("OIT_DEBUG_OVERFLOW".into()),
+            OitDebugView::DepthComplexity => defs.push("OIT_DEBUG_DEPTH_COMPLEXITY".into()),
+        }
+        if self.pass == OitRenderPass::Resolve
+            && self
+                .method
+                .stores_coverage(self.k_buffer, self.msaa_samples)
+        {
+            defs.push(ShaderDefVal::UInt(
+                "OIT_COVERAGE".to_string(),
+                self.msaa_samples,
+            ));
+        }
+        defs
+    }
+}
+
+impl SpecializedRenderPipeline for OitRenderPipeline {
+    type Key = OitRenderKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut defs = key.shader_defs();
+
+        // The passes running before the resolve passes read every fragment of the pixels
+        let (label, entry_point, format, blend, layout) = match key.pass {
+            OitRenderPass::Resolve => (
+                "render_oit_pipeline",
+                "fragment",
+                if key.hdr {
+                    ViewTarget::TEXTURE_FORMAT_HDR
+                } else {
+                    TextureFormat::bevy_default()
+                },
+                Some(BlendState {
+                    color: BlendComponent::OVER,
+                    alpha: BlendComponent::OVER,
+                }),
+                vec![
+                    self.view_bind_group_layout.clone(),
+                    self.oit_layers_bind_group_layout.clone(),
+                    self.depth_slice_bind_group_layout.clone(),
+                ],
+            ),
+            OitRenderPass::DepthOutput(alpha_threshold) => {
+                defs.push("OIT_ALL_FRAGMENTS".into());
+                defs.push(ShaderDefVal::UInt(
+                    "OIT_DEPTH_OUTPUT".to_string(),
+                    alpha_threshold,
+                ));
+                (
+                    "oit_depth_output_pipeline",
+                    "depth_output",
+                    DEPTH_OUTPUT_TEXTURE_FORMAT,
+                    None,
+                    vec![
+                        self.view_bind_group_layout.clone(),
+                        self.oit_layers_bind_group_layout.clone(),
+                    ],
+                )
+            }
+            OitRenderPass::MotionVectors => {
+                defs.push("OIT_ALL_FRAGMENTS".into());
+                defs.push("OIT_MOTION_VECTORS".into());
+                (
+                    "oit_motion_vectors_pipeline",
+                    "motion_vectors",
+                    MOTION_VECTOR_PREPASS_FORMAT,
+                    None,
+                    vec![
+                        self.motion_vectors_view_bind_group_layout.clone(),
+                        self.oit_layers_bind_group_layout.clone(),
+                    ],
+                )
+            }
+        };
+
+        RenderPipelineDescriptorBuilder::fullscreen()
+            .label(label)
+            .fragment(
+                OIT_RENDER_SHADER_HANDLE.typed(),
+                entry_point,
+                &[ColorTargetState {
+                    format,
+                    blend,
+                    write_mask: ColorWrites::ALL,
+                }],
+                &defs,
+            )
+            .multisample_state(MultisampleState {
+                count: key.msaa_samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            })
+            .layout(layout)
+            .build()
+    }
+}
+
+#[derive(Component, Deref)]
 pub struct OitRenderPipelineId(pub CachedRenderPipelineId);
 
+#[derive(Component, Deref)]
+pub struct OitDepthOutputPipelineId(pub CachedRenderPipelineId);
+
+#[derive(Component, Deref)]
+pub struct OitMotionVectorsPipelineId(pub CachedRenderPipelineId);
+
 pub fn queue_render_oit_pipeline(
     mut commands: Commands,
     pipeline_cache: Res<PipelineCache>,
-    draw_pipeline: Res<OitDrawPipeline>,
-    render_pipeline: Res<OitRenderPipeline>,
+    render_pipeline: Option<Res<OitRenderPipeline>>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<OitRenderPipeline>>,
+    views: Query<(
+        Entity,
+        &ExtractedView,
+        &OitCamera,
+        Option<&ViewPrepassTextures>,
+    )>,
     msaa: Res<Msaa>,
 ) {
-    let desc = RenderPipelineDescriptorBuilder::fullscreen()
-        .label("render_oit_pipeline")
-        .fragment(
-            OIT_RENDER_SHADER_HANDLE.typed(),
-            "fragment",
-            &[ColorTargetState {
-                format: TextureFormat::bevy_default(),
-                blend: Some(BlendState {
-                    color: BlendComponent::OVER,
-                    alpha: BlendComponent::OVER,
-                }),
-                // blend: None,
-                write_mask: ColorWrites::ALL,
-            }],
-            &[
-                ShaderDefVal::Int("OIT_LAYERS".to_string(), OIT_LAYERS as i32),
-                ShaderDefVal::Bool("MULTISAMPLED".to_string(), msaa.samples() > 1),
-            ],
-        )
-        .multisample_state(MultisampleState {
-            count: msaa.samples(),
-            mask: !0,
-            alpha_to_coverage_enabled: false,
-        })
-        .layout(vec![
-            render_pipeline.view_bind_group_layout.clone(),
-            draw_pipeline.oit_layers_bind_group_layout.clone(),
-        ])
-        .build();
-
-    let pipeline_id = pipeline_cache.queue_render_pipeline(desc);
-    commands.insert_resource(OitRenderPipelineId(pipeline_id));
+    // Only created when the device supports the storage buffers
+    let Some(render_pipeline) = render_pipeline else {
+        return;
+    };
+
+    for (entity, view, oit_camera, prepass_textures) in &views {
+        if !oit_camera.method.uses_layers() {
+            continue;
+        }
+
+        let key = OitRenderKey {
+            method: oit_camera.method,
+            layers: oit_camera.layers,
+            msaa_samples: msaa.samples(),
+            hdr: view.hdr,
+            k_buffer: oit_camera.k_buffer,
+            debug_view: oit_camera.debug_view,
+            pass: OitRenderPass::Resolve,
+        };
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &render_pipeline, key);
+        commands
+            .entity(entity)
+            .insert(OitRenderPipelineId(pipeline_id));
+
+        if let Some(depth_output) = oit_camera.depth_output {
+            let key = OitRenderKey {
+                msaa_samples: 1,
+                debug_view: OitDebugView::None,
+                pass: OitRenderPass::DepthOutput(depth_output.alpha_threshold()),
+                ..key
+            };
+            let pipeline_id = pipelines.specialize(&pipeline_cache, &render_pipeline, key);
+            commands
+                .entity(entity)
+                .insert(OitDepthOutputPipelineId(pipeline_id));
+        }
+
+        if prepass_textures.is_some_and(|textures| textures.motion_vectors.is_some()) {
+            let key = OitRenderKey {
+                debug_view: OitDebugView::None,
+                pass: OitRenderPass::MotionVectors,
+                ..key
+            };
+            let pipeline_id = pipelines.specialize(&pipeline_cache, &render_pipeline, key);
+            commands
+                .entity(entity)
+                .insert(OitMotionVectorsPipelineId(pipeline_id));
+        }
+    }
 }
@@ -0,0 +1,580 @@
+//! A CPU implementation of [`OitMethod::ABuffer`] for a single pixel.
+//!
+//! This mirrors what `oit_draw.wgsl` and `oit_render.wgsl` do on the GPU, including the packing
+//! of the layers, so the results can be compared with the GPU or used to check the blending
+//! without a GPU.
+//!
+//! ```
+//! use bevy::prelude::*;
+//! use bevy_oit::reference::{OitFragment, OitReference};
+//!
+//! let reference = OitReference {
+//!     layers: 8,
+//!     tail_blend: false,
+//!     k_buffer: false,
+//!     hdr: false,
+//! };
+//! let color = reference.render(
+//!     Vec4::new(0.0, 0.0, 0.0, 1.0),
+//!     &[
+//!         OitFragment::new(Vec4::new(1.0, 0.0, 0.0, 0.5), 0.1),
+//!         OitFragment::new(Vec4::new(0.0, 0.0, 1.0, 0.5), 0.2),
+//!     ],
+//! );
+//! ```
+//!
+//! [`OitMethod::ABuffer`]: crate::OitMethod::ABuffer
+
+use bevy::prelude::*;
+
+use crate::OitCamera;
+
+/// A transparent fragment drawn on a pixel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OitFragment {
+    /// The color returned by the material, it isn't premultiplied.
+    pub color: Vec4,
+    /// The depth of the fragment, bevy uses a reversed z so closer fragments have a bigger depth.
+    pub depth: f32,
+}
+
+impl OitFragment {
+    #[must_use]
+    pub fn new(color: Vec4, depth: f32) -> Self {
+        Self { color, depth }
+    }
+}
+
+/// The settings of the [`OitCamera`] that affect the result of a pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OitReference {
+    /// See [`OitCamera::layers`]
+    pub layers: usize,
+    /// See [`OitCamera::tail_blend`]
+    pub tail_blend: bool,
+    /// See [`OitCamera::k_buffer`]
+    pub k_buffer: bool,
+    /// Whether the camera is `hdr`, this changes how the layers are packed.
+    pub hdr: bool,
+}
+
+impl OitReference {
+    #[must_use]
+    pub fn from_camera(oit_camera: &OitCamera, hdr: bool) -> Self {
+        Self {
+            layers: oit_camera.layers,
+            tail_blend: oit_camera.tail_blend,
+            // The k-buffer is ignored by hdr cameras
+            k_buffer: oit_camera.k_buffer && !hdr,
+            hdr,
+        }
+    }
+
+    /// Draws the fragments in order and resolves the pixel on top of `target`.
+    ///
+    /// `target` is the color of the view target after the opaque and transparent passes.
+    #[must_use]
+    pub fn render(&self, target: Vec4, fragments: &[OitFragment]) -> Vec4 {
+        let mut pixel = OitPixel::new(*self);
+        let mut target = target;
+        for fragment in fragments {
+            pixel.draw(*fragment, &mut target);
+        }
+        pixel.resolve(target)
+    }
+}
+
+/// The layers of a single pixel.
+#[derive(Clone, Debug)]
+pub struct OitPixel {
+    pub settings: OitReference,
+    /// The packed fragments, see [`pack_layer`]
+    pub layers: Vec<UVec2>,
+    /// The packed fragments sorted from front to back when using [`OitReference::k_buffer`],
+    /// see [`pack_k_buffer_layer`]. Empty layers are 0.
+    pub k_buffer_layers: Vec<u32>,
+    /// The number of fragments drawn on the pixel, this can be bigger than the number of layers.
+    pub counter: usize,
+}
+
+impl OitPixel {
+    #[must_use]
+    pub fn new(settings: OitReference) -> Self {
+        Self {
+            settings,
+            layers: Vec::with_capacity(settings.layers),
+            k_buffer_layers: vec![
+                0;
+                if settings.k_buffer {
+                    settings.layers
+                } else {
+                    0
+                }
+            ],
+            counter: 0,
+        }
+    }
+
+    /// Mirrors `oit_draw()`.
+    ///
+    /// The fragment is stored if there's a free layer. Otherwise it's either dropped or, with
+    /// [`OitReference::tail_blend`], alpha blended directly on the target.
+    ///
+    /// With [`OitReference::k_buffer`] the fragment is inserted in the sorted layers and the
+    /// farthest fragment is the one that gets dropped or tail blended.
+    pub fn draw(&mut self, fragment: OitFragment, target: &mut Vec4) {
+        if self.settings.k_buffer {
+            self.counter += 1;
+
+            let mut packed = pack_k_buffer_layer(fragment.color, fragment.depth);
+            let mut farthest = fragment.color;
+            for layer in &mut self.k_buffer_layers {
+                // atomicMax()
+                let previous = *layer;
+                *layer = previous.max(packed);
+                if previous == 0 {
+                    return;
+                }
+                if previous < packed {
+                    packed = previous;
+                    farthest = unpack_k_buffer_layer_color(previous);
+                }
+            }
+
+            if self.settings.tail_blend {
+                *target = alpha_blend(farthest, *target);
+            }
+            return;
+        }
+
+        let layer_id = self.counter;
+        self.counter += 1;
+
+        if layer_id >= self.settings.layers {
+            if self.settings.tail_blend {
+                *target = alpha_blend(fragment.color, *target);
+            }
+            return;
+        }
+
+        self.layers.push(pack_layer(
+            fragment.color,
+            fragment.depth,
+            self.settings.hdr,
+        ));
+    }
+
+    /// Mirrors the fragment shader of `oit_render.wgsl` and the blending of its pipeline.
+    ///
+    /// The stored layers are sorted from front to back, blended together and the result is
+    /// blended on top of `target`. The layers are cleared for the next frame.
+    ///
+    /// Fragments with the same packed depth are ordered by the rest of their packed bits, like
+    /// `layer_in_front()`, so the result doesn't depend on the order they were drawn in.
+    #[must_use]
+    pub fn resolve(&mut self, target: Vec4) -> Vec4 {
+        if self.counter == 0 {
+            return target;
+        }
+
+        if self.settings.k_buffer {
+            let counter = self.counter.min(self.settings.layers);
+            let mut final_color = Vec4::ZERO;
+            for layer in &mut self.k_buffer_layers[..counter] {
+                let color = unpack_k_buffer_layer_color(*layer);
+                let base_color = (color.truncate() * color.w).extend(color.w);
+                final_color = blend(final_color, base_color);
+                *layer = 0;
+            }
+
+            self.counter = 0;
+            return over(final_color, target);
+        }
+
+        let hdr = self.settings.hdr;
+        let mut layers = std::mem::take(&mut self.layers);
+        // Closer fragments have a bigger depth, positive floats keep the same order as their bits
+        layers.sort_by_key(|layer| std::cmp::Reverse((layer.y, layer.x)));
+
+        let mut final_color = Vec4::ZERO;
+        for layer in layers {
+            let color = unpack_layer_color(layer, hdr);
+            let base_color = (color.truncate() * color.w).extend(color.w);
+            final_color = blend(final_color, base_color);
+        }
+
+        self.counter = 0;
+        over(final_color, target)
+    }
+}
+
+/// OVER operator using premultiplied alpha, `color_a` is in front of `color_b`.
+///
+/// See: <https://en.wikipedia.org/wiki/Alpha_compositing>
+#[must_use]
+pub fn blend(color_a: Vec4, color_b: Vec4) -> Vec4 {
+    let final_color = color_a.truncate() + (1.0 - color_a.w) * color_b.truncate();
+    let alpha = color_a.w + (1.0 - color_a.w) * color_b.w;
+    final_color.extend(alpha)
+}
+
+/// The blending of the resolve pass, `src` is premultiplied.
+fn over(src: Vec4, dst: Vec4) -> Vec4 {
+    src + dst * (1.0 - src.w)
+}
+
+/// The blending of the draw pass used for tail blending, `src` isn't premultiplied.
+fn alpha_blend(src: Vec4, dst: Vec4) -> Vec4 {
+    let color = src.truncate() * src.w + dst.truncate() * (1.0 - src.w);
+    let alpha = src.w + dst.w * (1.0 - src.w);
+    color.extend(alpha)
+}
+
+/// Mirrors `pack_layer()` from `oit_layer.wgsl`.
+#[must_use]
+#[allow(clippy::cast_sign_loss)]
+pub fn pack_layer(color: Vec4, depth: f32, hdr: bool) -> UVec2 {
+    if hdr {
+        let alpha = (color.w.clamp(0.0, 1.0) * 255.0).round() as u32;
+        UVec2::new(
+            pack_rgb9e5(color.truncate()),
+            (depth.to_bits() & 0xFFFF_FF00) | alpha,
+        )
+    } else {
+        UVec2::new(pack4x8unorm(color), depth.to_bits())
+    }
+}
+
+/// Mirrors `unpack_layer_color()` from `oit_layer.wgsl`.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn unpack_layer_color(layer: UVec2, hdr: bool) -> Vec4 {
+    if hdr {
+        unpack_rgb9e5(layer.x).extend((layer.y & 0xFF) as f32 / 255.0)
+    } else {
+        unpack4x8unorm(layer.x)
+    }
+}
+
+/// Mirrors `unpack_layer_depth()` from `oit_layer.wgsl`.
+#[must_use]
+pub fn unpack_layer_depth(layer: UVec2) -> f32 {
+    f32::from_bits(layer.y)
+}
+
+/// Mirrors `pack_k_buffer_layer()` from `oit_layer.wgsl`.
+#[must_use]
+#[allow(clippy::cast_sign_loss)]
+pub fn pack_k_buffer_layer(color: Vec4, depth: f32) -> u32 {
+    let c = (color.clamp(Vec4::ZERO, Vec4::ONE) * 15.0)
+        .round()
+        .as_uvec4();
+    (pack_f16(depth) << 16) | (c.x << 12) | (c.y << 8) | (c.z << 4) | c.w
+}
+
+/// Mirrors `unpack_k_buffer_layer_color()` from `oit_layer.wgsl`.
+#[must_use]
+pub fn unpack_k_buffer_layer_color(layer: u32) -> Vec4 {
+    UVec4::new(
+        (layer >> 12) & 0xF,
+        (layer >> 8) & 0xF,
+        (layer >> 4) & 0xF,
+        layer & 0xF,
+    )
+    .as_vec4()
+        / 15.0
+}
+
+/// Mirrors the first component of the WGSL `pack2x16float()` builtin, rounding to the nearest
+/// even value.
+#[must_use]
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+pub fn pack_f16(value: f32) -> u32 {
+    let bits = value.to_bits();
+    let sign = (bits >> 16) & 0x8000;
+    let exponent = ((bits >> 23) & 0xFF) as i32 - 127 + 15;
+    let mantissa = bits & 0x7F_FFFF;
+
+    if exponent >= 31 {
+        return sign | 0x7C00;
+    }
+    if exponent < -10 {
+        return sign;
+    }
+
+    // Subnormals shift the implicit leading bit into the mantissa
+    let (half, shift) = if exponent <= 0 {
+        (0, (14 - exponent) as u32)
+    } else {
+        ((exponent as u32) << 10, 13)
+    };
+    let mantissa = if exponent <= 0 {
+        mantissa | 0x80_0000
+    } else {
+        mantissa
+    };
+    let half = half | (mantissa >> shift);
+    let remainder = mantissa & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let round_up = remainder > halfway || (remainder == halfway && half & 1 == 1);
+    sign | (half + u32::from(round_up))
+}
+
+/// Mirrors the WGSL `pack4x8unorm()` builtin.
+#[must_use]
+#[allow(clippy::cast_sign_loss)]
+pub fn pack4x8unorm(color: Vec4) -> u32 {
+    color
+        .to_array()
+        .iter()
+        .enumerate()
+        .fold(0, |packed, (i, channel)| {
+            let channel = (0.5 + 255.0 * channel.clamp(0.0, 1.0)).floor() as u32;
+            packed | (channel << (i * 8))
+        })
+}
+
+/// Mirrors the WGSL `unpack4x8unorm()` builtin.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn unpack4x8unorm(packed: u32) -> Vec4 {
+    Vec4::from_array(std::array::from_fn(|i| {
+        ((packed >> (i * 8)) & 0xFF) as f32 / 255.0
+    }))
+}
+
+const RGB9E5_EXPONENT_BIAS: f32 = 15.0;
+const RGB9E5_MANTISSA_BITS: f32 = 9.0;
+const RGB9E5_MAX: f32 = 65408.0;
+
+/// Mirrors `pack_rgb9e5()` from `oit_layer.wgsl`.
+#[must_use]
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::float_cmp)]
+pub fn pack_rgb9e5(color: Vec3) -> u32 {
+    let rgb = color.clamp(Vec3::ZERO, Vec3::splat(RGB9E5_MAX));
+    let max_channel = rgb.max_element();
+
+    let mut exponent =
+        (-RGB9E5_EXPONENT_BIAS - 1.0).max(max_channel.log2().floor()) + 1.0 + RGB9E5_EXPONENT_BIAS;
+    let max_mantissa =
+        (max_channel / (exponent - RGB9E5_EXPONENT_BIAS - RGB9E5_MANTISSA_BITS).exp2() + 0.5)
+            .floor();
+    if max_mantissa == RGB9E5_MANTISSA_BITS.exp2() {
+        exponent += 1.0;
+    }
+
+    let mantissa = (rgb / (exponent - RGB9E5_EXPONENT_BIAS - RGB9E5_MANTISSA_BITS).exp2() + 0.5)
+        .floor()
+        .as_uvec3();
+    mantissa.x | (mantissa.y << 9) | (mantissa.z << 18) | ((exponent as u32) << 27)
+}
+
+/// Mirrors `unpack_rgb9e5()` from `oit_layer.wgsl`.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn unpack_rgb9e5(packed: u32) -> Vec3 {
+    let exponent = (packed >> 27) as f32;
+    let mantissa = UVec3::new(
+        packed & 0x1FF,
+        (packed >> 9) & 0x1FF,
+        (packed >> 18) & 0x1FF,
+    );
+    mantissa.as_vec3() * (exponent - RGB9E5_EXPONENT_BIAS - RGB9E5_MANTISSA_BITS).exp2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LDR: OitReference = OitReference {
+        layers: 8,
+        tail_blend: false,
+        k_buffer: false,
+        hdr: false,
+    };
+
+    /// An opaque black view target
+    const TARGET: Vec4 = Vec4::new(0.0, 0.0, 0.0, 1.0);
+
+    fn fragments() -> [OitFragment; 3] {
+        let near = OitFragment::new(Vec4::new(0.0, 0.0, 1.0, 0.5), 0.3);
+        let middle = OitFragment::new(Vec4::new(0.0, 1.0, 0.0, 0.5), 0.2);
+        let far = OitFragment::new(Vec4::new(1.0, 0.0, 0.0, 0.5), 0.1);
+        [near, middle, far]
+    }
+
+    #[test]
+    fn blends_out_of_order_fragments_front_to_back() {
+        let [near, middle, far] = fragments();
+
+        let color = LDR.render(TARGET, &[middle, far, near]);
+        // near + 0.5 * (middle + 0.5 * far) on top of the target
+        let expected = Vec4::new(0.125, 0.25, 0.5, 1.0);
+        assert!(color.abs_diff_eq(expected, 1.0 / 255.0), "{color:?}");
+
+        for order in [
+            [far, middle, near],
+            [near, middle, far],
+            [near, far, middle],
+        ] {
+            assert_eq!(LDR.render(TARGET, &order), color);
+        }
+    }
+
+    #[test]
+    fn drops_overflowing_fragments_without_tail_blend() {
+        let settings = OitReference { layers: 2, ..LDR };
+        let order = [fragments()[2], fragments()[0], fragments()[1]];
+
+        assert_eq!(
+            settings.render(TARGET, &order),
+            settings.render(TARGET, &order[..2])
+        );
+    }
+
+    #[test]
+    fn tail_blends_overflowing_fragments() {
+        let settings = OitReference {
+            layers: 2,
+            tail_blend: true,
+            ..LDR
+        };
+        let order = [fragments()[2], fragments()[0], fragments()[1]];
+
+        // The fragment that doesn't fit is blended on the target before the resolve
+        let target = alpha_blend(order[2].color, TARGET);
+        let color = settings.render(TARGET, &order);
+        assert_eq!(color, settings.render(target, &order[..2]));
+        assert_ne!(color, settings.render(TARGET, &order[..2]));
+    }
+
+    #[test]
+    fn k_buffer_keeps_the_nearest_fragments() {
+        let settings = OitReference {
+            layers: 2,
+            k_buffer: true,
+            ..LDR
+        };
+        let [near, middle, far] = fragments();
+
+        let expected = settings.render(TARGET, &[near, middle]);
+        for order in [
+            [far, middle, near],
+            [near, middle, far],
+            [middle, far, near],
+        ] {
+            assert_eq!(settings.render(TARGET, &order), expected);
+        }
+
+        // The farthest fragment is the one that gets tail blended, with the k-buffer precision
+        let settings = OitReference {
+            tail_blend: true,
+            ..settings
+        };
+        let far_color = unpack_k_buffer_layer_color(pack_k_buffer_layer(far.color, far.depth));
+        assert_eq!(
+            settings.render(TARGET, &[far, near, middle]),
+            settings.render(alpha_blend(far_color, TARGET), &[near, middle])
+        );
+    }
+
+    #[test]
+    fn breaks_equal_depth_ties_independently_of_the_draw_order() {
+        let red = OitFragment::new(Vec4::new(1.0, 0.0, 0.0, 0.5), 0.2);
+        let blue = OitFragment::new(Vec4::new(0.0, 0.0, 1.0, 0.5), 0.2);
+
+        let hdr = OitReference { hdr: true, ..LDR };
+        let k_buffer = OitReference {
+            k_buffer: true,
+            ..LDR
+        };
+        for settings in [LDR, hdr, k_buffer] {
+            assert_eq!(
+                settings.render(TARGET, &[red, blue]),
+                settings.render(TARGET, &[blue, red])
+            );
+        }
+    }
+
+    #[test]
+    fn pack_layer_round_trips() {
+        let depth = 0.123_456_7;
+
+        let color = Vec4::new(1.0, 128.0 / 255.0, 0.0, 64.0 / 255.0);
+        let layer = pack_layer(color, depth, false);
+        assert_eq!(unpack_layer_color(layer, false), color);
+        assert_eq!(unpack_layer_depth(layer).to_bits(), depth.to_bits());
+
+        // The hdr layers keep colors above 1.0 but the alpha replaces the 8 least significant
+        // bits of the depth
+        let color = Vec4::new(4.0, 0.5, 0.25, 64.0 / 255.0);
+        let layer = pack_layer(color, depth, true);
+        assert_eq!(unpack_layer_color(layer, true), color);
+        assert!((unpack_layer_depth(layer) - depth).abs() <= depth * 2f32.powi(-15));
+    }
+
+    #[test]
+    fn pack4x8unorm_round_trips() {
+        for i in 0..=255u8 {
+            let value = f32::from(i) / 255.0;
+            let color = Vec4::new(value, f32::from(255 - i) / 255.0, 0.0, 1.0);
+            assert_eq!(unpack4x8unorm(pack4x8unorm(color)), color);
+        }
+    }
+
+    #[test]
+    fn rgb9e5_limits() {
+        assert_eq!(pack_rgb9e5(Vec3::ZERO), 0);
+        assert_eq!(unpack_rgb9e5(pack_rgb9e5(Vec3::ZERO)), Vec3::ZERO);
+
+        let max = Vec3::splat(RGB9E5_MAX);
+        assert_eq!(unpack_rgb9e5(pack_rgb9e5(max)), max);
+        // The channels share the exponent of the biggest one
+        assert_eq!(
+            unpack_rgb9e5(pack_rgb9e5(Vec3::new(1e6, -1.0, 1.0))),
+            Vec3::new(RGB9E5_MAX, 0.0, 0.0)
+        );
+
+        // The smallest value uses the smallest exponent with a mantissa of 1
+        let smallest = Vec3::splat(2f32.powi(-24));
+        assert_eq!(unpack_rgb9e5(pack_rgb9e5(smallest)), smallest);
+        assert_eq!(unpack_rgb9e5(pack_rgb9e5(smallest * 0.4)), Vec3::ZERO);
+
+        for value in [0.1, 1.0, 1.7, 300.0, 12345.6] {
+            let unpacked = unpack_rgb9e5(pack_rgb9e5(Vec3::splat(value)));
+            assert!(unpacked.abs_diff_eq(Vec3::splat(value), value * 2f32.powi(-8)));
+        }
+    }
+
+    #[test]
+    fn pack_f16_matches_known_values() {
+        assert_eq!(pack_f16(0.0), 0);
+        assert_eq!(pack_f16(0.5), 0x3800);
+        assert_eq!(pack_f16(1.0), 0x3C00);
+        assert_eq!(pack_f16(65504.0), 0x7BFF);
+        assert_eq!(pack_f16(1e6), 0x7C00);
+        // Subnormals round to the nearest even value
+        assert_eq!(pack_f16(2f32.powi(-24)), 1);
+        assert_eq!(pack_f16(2f32.powi(-25)), 0);
+        assert_eq!(pack_f16(3.0 * 2f32.powi(-25)), 2);
+    }
+
+    #[test]
+    fn k_buffer_depth_precision() {
+        // Close to the near plane the depths are 2^-11 apart
+        assert_eq!(pack_f16(1.0 - 2f32.powi(-12)), pack_f16(1.0));
+        assert!(pack_f16(1.0 - 2f32.powi(-11)) < pack_f16(1.0));
+        // Far away the subnormals keep small depths apart
+        assert!(0 < pack_f16(1e-6));
+        assert!(pack_f16(1e-6) < pack_f16(2e-6));
+
+        // The packed layers keep the order of the depths
+        let mut previous = 0;
+        for i in 0..=1000u16 {
+            let layer = pack_k_buffer_layer(Vec4::ONE, f32::from(i) / 1000.0);
+            assert!(layer >= previous);
+            previous = layer;
+        }
+    }
+}
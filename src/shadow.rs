@@ -0,0 +1,285 @@
+use std::{hash::Hash, marker::PhantomData};
+
+use bevy::{
+    pbr::{
+        setup_morph_and_skinning_defs, CascadesVisibleEntities, CubemapVisibleEntities, DrawMesh,
+        ExtractedDirectionalLight, ExtractedPointLight, LightEntity, MeshLayouts, MeshPipelineKey,
+        NotShadowCaster, PrepassPipeline, SetMeshBindGroup, SetPrepassViewBindGroup, Shadow,
+        ViewLightEntities, PREPASS_SHADER_HANDLE,
+    },
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_phase::{DrawFunctions, RenderPhase, SetItemPipeline},
+        render_resource::{
+            BindGroupLayout, CompareFunction, DepthBiasState, DepthStencilState, FragmentState,
+            MultisampleState, PipelineCache, PrimitiveState, RenderPipelineDescriptor, ShaderRef,
+            SpecializedMeshPipeline, SpecializedMeshPipelineError, SpecializedMeshPipelines,
+            StencilState, TextureFormat, VertexState,
+        },
+        renderer::RenderDevice,
+        view::VisibleEntities,
+    },
+};
+
+use crate::material::{OitMaterial, RenderOitMaterials, SetOitMaterialBindGroup};
+
+/// Renders the meshes of an [`OitMaterial`] in the shadow maps of the lights.
+///
+/// This mirrors the prepass pipeline bevy uses for the shadows of its materials. The vertex shader
+/// is always the prepass one and the fragment shader is
+/// [`OitMaterial::shadow_fragment_shader`].
+#[derive(Resource)]
+pub struct OitShadowPipeline<M: OitMaterial> {
+    pub view_layout: BindGroupLayout,
+    pub mesh_layouts: MeshLayouts,
+    pub oit_material_bind_group_layout: BindGroupLayout,
+    pub fragment_shader: Option<Handle<Shader>>,
+    marker: PhantomData<M>,
+}
+
+impl<M: OitMaterial> FromWorld for OitShadowPipeline<M> {
+    fn from_world(world: &mut World) -> Self {
+        // The view bind group of the shadow views is created by bevy with this layout
+        let prepass_pipeline = world.resource::<PrepassPipeline<StandardMaterial>>();
+        let view_layout = prepass_pipeline.view_layout_no_motion_vectors.clone();
+        let mesh_layouts = prepass_pipeline.mesh_layouts.clone();
+
+        let asset_server = world.resource::<AssetServer>();
+        let render_device = world.resource::<RenderDevice>();
+
+        OitShadowPipeline {
+            view_layout,
+            mesh_layouts,
+            oit_material_bind_group_layout: M::bind_group_layout(render_device),
+            fragment_shader: match M::shadow_fragment_shader() {
+                ShaderRef::Default => None,
+                ShaderRef::Handle(handle) => Some(handle),
+                ShaderRef::Path(path) => Some(asset_server.load(path)),
+            },
+            marker: PhantomData,
+        }
+    }
+}
+
+/// A key uniquely identifying a specialized [`OitShadowPipeline`].
+pub struct OitShadowKey<M: OitMaterial> {
+    pub mesh_key: MeshPipelineKey,
+    pub bind_group_data: M::Data,
+}
+
+impl<M: OitMaterial> Eq for OitShadowKey<M> where M::Data: PartialEq {}
+
+impl<M: OitMaterial> PartialEq for OitShadowKey<M>
+where
+    M::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.mesh_key == other.mesh_key && self.bind_group_data == other.bind_group_data
+    }
+}
+
+impl<M: OitMaterial> Clone for OitShadowKey<M>
+where
+    M::Data: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            mesh_key: self.mesh_key,
+            bind_group_data: self.bind_group_data.clone(),
+        }
+    }
+}
+
+impl<M: OitMaterial> Hash for OitShadowKey<M>
+where
+    M::Data: Hash,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.mesh_key.hash(state);
+        self.bind_group_data.hash(state);
+    }
+}
+
+impl<M: OitMaterial> SpecializedMeshPipeline for OitShadowPipeline<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = OitShadowKey<M>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut shader_defs = vec!["DEPTH_PREPASS".into()];
+        let mut vertex_attributes = vec![Mesh::ATTRIBUTE_POSITION.at_shader_location(0)];
+        if layout.contains(Mesh::ATTRIBUTE_UV_0) {
+            shader_defs.push("VERTEX_UVS".into());
+            vertex_attributes.push(Mesh::ATTRIBUTE_UV_0.at_shader_location(1));
+        }
+        // Directional lights clamp the depth of the meshes behind the near plane of the cascade
+        // and write the unclamped depth in the fragment shader
+        let depth_clamp_ortho = key.mesh_key.contains(MeshPipelineKey::DEPTH_CLAMP_ORTHO);
+        if depth_clamp_ortho {
+            shader_defs.push("DEPTH_CLAMP_ORTHO".into());
+            shader_defs.push("PREPASS_FRAGMENT".into());
+        }
+
+        let mesh_layout = setup_morph_and_skinning_defs(
+            &self.mesh_layouts,
+            layout,
+            4,
+            &key.mesh_key,
+            &mut shader_defs,
+            &mut vertex_attributes,
+        );
+        let vertex_buffer_layout = layout.get_layout(&vertex_attributes)?;
+
+        let fragment_shader = match &self.fragment_shader {
+            Some(shader) => Some(shader.clone()),
+            None if depth_clamp_ortho => Some(PREPASS_SHADER_HANDLE.typed()),
+            None => None,
+        };
+
+        let mut descriptor = RenderPipelineDescriptor {
+            label: Some("oit_shadow_pipeline".into()),
+            layout: vec![
+                self.view_layout.clone(),
+                self.oit_material_bind_group_layout.clone(),
+                mesh_layout,
+            ],
+            vertex: VertexState {
+                shader: PREPASS_SHADER_HANDLE.typed(),
+                entry_point: "vertex".into(),
+                shader_defs: shader_defs.clone(),
+                buffers: vec![vertex_buffer_layout],
+            },
+            fragment: fragment_shader.map(|shader| FragmentState {
+                shader,
+                entry_point: "fragment".into(),
+                shader_defs,
+                targets: vec![],
+            }),
+            primitive: PrimitiveState {
+                topology: key.mesh_key.primitive_topology(),
+                cull_mode: None,
+                ..default()
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState::default(),
+            push_constant_ranges: vec![],
+        };
+
+        M::specialize_shadow(self, &mut descriptor, layout, key)?;
+        Ok(descriptor)
+    }
+}
+
+pub(crate) type DrawOitShadow<M> = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetOitMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    DrawMesh,
+);
+
+/// Adds the meshes of an [`OitMaterial`] to the shadow phases of the lights.
+///
+/// This is the same as bevy's `queue_shadows` for materials.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_oit_shadows<M: OitMaterial>(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    shadow_pipeline: Res<OitShadowPipeline<M>>,
+    casting_meshes: Query<(&Handle<Mesh>, &Handle<M>), Without<NotShadowCaster>>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderOitMaterials<M>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<OitShadowPipeline<M>>>,
+    pipeline_cache: Res<PipelineCache>,
+    view_lights: Query<(Entity, &ViewLightEntities)>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    point_light_entities: Query<&CubemapVisibleEntities, With<ExtractedPointLight>>,
+    directional_light_entities: Query<&CascadesVisibleEntities, With<ExtractedDirectionalLight>>,
+    spot_light_entities: Query<&VisibleEntities, With<ExtractedPointLight>>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_function = shadow_draw_functions.read().id::<DrawOitShadow<M>>();
+
+    for (view_entity, view_lights) in &view_lights {
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let Ok((light_entity, mut shadow_phase)) =
+                view_light_shadow_phases.get_mut(view_light_entity)
+            else {
+                continue;
+            };
+            let visible_entities = match light_entity {
+                LightEntity::Directional {
+                    light_entity,
+                    cascade_index,
+                } => directional_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .and_then(|entities| entities.entities.get(&view_entity))
+                    .and_then(|cascades| cascades.get(*cascade_index)),
+                LightEntity::Point {
+                    light_entity,
+                    face_index,
+                } => point_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .map(|entities| entities.get(*face_index)),
+                LightEntity::Spot { light_entity } => spot_light_entities.get(*light_entity).ok(),
+            };
+            let Some(visible_entities) = visible_entities else {
+                continue;
+            };
+            let is_directional_light = matches!(light_entity, LightEntity::Directional { .. });
+
+            for entity in visible_entities.iter().copied() {
+                let Ok((mesh_handle, material_handle)) = casting_meshes.get(entity) else {
+                    continue;
+                };
+                let (Some(mesh), Some(material)) = (
+                    render_meshes.get(mesh_handle),
+                    render_materials.get(material_handle),
+                ) else {
+                    continue;
+                };
+
+                let mut mesh_key =
+                    MeshPipelineKey::from_primitive_topology(mesh.primitive_topology)
+                        | MeshPipelineKey::DEPTH_PREPASS;
+                if mesh.morph_targets.is_some() {
+                    mesh_key |= MeshPipelineKey::MORPH_TARGETS;
+                }
+                if is_directional_light {
+                    mesh_key |= MeshPipelineKey::DEPTH_CLAMP_ORTHO;
+                }
+                let key = OitShadowKey {
+                    mesh_key,
+                    bind_group_data: material.key.clone(),
+                };
+                let Ok(pipeline) =
+                    pipelines.specialize(&pipeline_cache, &shadow_pipeline, key, &mesh.layout)
+                else {
+                    continue;
+                };
+
+                shadow_phase.add(Shadow {
+                    draw_function,
+                    pipeline,
+                    entity,
+                    distance: 0.0,
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use bevy::{
+    core_pipeline::{
+        core_3d::Transparent3d,
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    pbr::{
+        queue_material_meshes, DrawMesh, EnvironmentMapLight, MaterialPipeline,
+        MaterialPipelineKey, MeshUniform, RenderMaterials, ScreenSpaceAmbientOcclusionSettings,
+        SetMaterialBindGroup, SetMeshBindGroup, SetMeshViewBindGroup, StandardMaterialKey,
+    },
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_phase::{AddRenderCommand, DrawFunctions, RenderPhase, SetItemPipeline},
+        render_resource::{
+            PipelineCache, RenderPipelineDescriptor, SpecializedMeshPipeline,
+            SpecializedMeshPipelineError, SpecializedMeshPipelines,
+        },
+        view::{ExtractedView, VisibleEntities},
+        Render, RenderApp, RenderSet,
+    },
+    utils::HashSet,
+};
+
+use crate::{
+    pipeline::{
+        oit_mesh_key, oit_view_key, specialize_oit_draw_pipeline, OitKey, OitLayersBindGroupLayout,
+    },
+    OitCamera, OitMethod, OitPhaseItem, SetOitLayersBindGroup, OIT_PBR_SHADER_HANDLE,
+};
+
+/// Renders [`StandardMaterial`] using [`AlphaMode::Blend`] in the OIT phase for cameras with
+/// [`OitCamera::standard_materials`] enabled.
+///
+/// The material bind groups are the ones prepared by bevy's `MaterialPlugin`.
+pub struct OitStandardMaterialPlugin;
+impl Plugin for OitStandardMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<SpecializedMeshPipelines<OitStandardMaterialPipeline>>()
+            .add_render_command::<OitPhaseItem, DrawOitStandardMaterial>()
+            .add_systems(
+                Render,
+                (
+                    queue_standard_material_oit_phase.in_set(RenderSet::Queue),
+                    remove_oit_transparent_items
+                        .in_set(RenderSet::Queue)
+                        .after(queue_standard_material_oit_phase)
+                        .after(queue_material_meshes::<StandardMaterial>),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.init_resource::<OitStandardMaterialPipeline>();
+    }
+}
+
+#[derive(Resource)]
+pub struct OitStandardMaterialPipeline {
+    material_pipeline: MaterialPipeline<StandardMaterial>,
+    oit_layers_bind_group_layout: OitLayersBindGroupLayout,
+}
+
+impl FromWorld for OitStandardMaterialPipeline {
+    fn from_world(world: &mut World) -> Self {
+        world.init_resource::<OitLayersBindGroupLayout>();
+
+        OitStandardMaterialPipeline {
+            material_pipeline: world
+                .resource::<MaterialPipeline<StandardMaterial>>()
+                .clone(),
+            oit_layers_bind_group_layout: world.resource::<OitLayersBindGroupLayout>().clone(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct OitStandardMaterialKey {
+    pub oit_key: OitKey,
+    pub bind_group_data: StandardMaterialKey,
+}
+
+impl SpecializedMeshPipeline for OitStandardMaterialPipeline {
+    type Key = OitStandardMaterialKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut desc = specialize_oit_draw_pipeline(
+            &self.material_pipeline.mesh_pipeline,
+            &self.material_pipeline.material_layout,
+            &self.oit_layers_bind_group_layout,
+            key.oit_key,
+            layout,
+        )?;
+
+        if let Some(frag) = desc.fragment.as_mut() {
+            frag.shader = OIT_PBR_SHADER_HANDLE.typed();
+        }
+
+        // This takes care of the normal map, cull mode and depth bias
+        <StandardMaterial as Material>::specialize(
+            &self.material_pipeline,
+            &mut desc,
+            layout,
+            MaterialPipelineKey {
+                mesh_key: key.oit_key.mesh_key,
+                bind_group_data: key.bind_group_data,
+            },
+        )?;
+
+        Ok(desc)
+    }
+}
+
+type DrawOitStandardMaterial = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMaterialBindGroup<StandardMaterial, 1>,
+    SetMeshBindGroup<2>,
+    SetOitLayersBindGroup<3>,
+    DrawMesh,
+);
+
+#[allow(clippy::too_many_arguments)]
+fn queue_standard_material_oit_phase(
+    draw_functions: Res<DrawFunctions<OitPhaseItem>>,
+    pipeline: Res<OitStandardMaterialPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<OitStandardMaterialPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<StandardMaterial>>,
+    images: Res<RenderAssets<Image>>,
+    meshes: Query<(
+        Entity,
+        &Handle<Mesh>,
+        &MeshUniform,
+        &Handle<StandardMaterial>,
+    )>,
+    mut views: Query<(
+        &ExtractedView,
+        &OitCamera,
+        &VisibleEntities,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        Option<&EnvironmentMapLight>,
+        Option<&ScreenSpaceAmbientOcclusionSettings>,
+        &mut RenderPhase<OitPhaseItem>,
+    )>,
+    msaa: Res<Msaa>,
+) {
+    let draw_function = draw_functions.read().id::<DrawOitStandardMaterial>();
+
+    for (
+        view,
+        oit_camera,
+        visible_entities,
+        tonemapping,
+        dither,
+        environment_map,
+        ssao,
+        mut oit_phase,
+    ) in &mut views
+    {
+        // Bevy already queued them in its transparent phase
+        if !oit_camera.standard_materials || oit_camera.method == OitMethod::TransparentPhase {
+            continue;
+        }
+
+        let view_matrix = view.transform.compute_matrix();
+        let inv_view_row_2 = view_matrix.inverse().row(2);
+
+        let view_key = oit_view_key(
+            view,
+            *msaa,
+            &images,
+            tonemapping,
+            dither,
+            environment_map,
+            ssao,
+        );
+
+        for visible_entity in visible_entities.entities.iter().copied() {
+            let Ok((entity, mesh_handle, mesh_uniform, material_handle)) =
+                meshes.get(visible_entity)
+            else {
+                continue;
+            };
+            let Some(material) = render_materials.get(material_handle) else {
+                continue;
+            };
+            if material.properties.alpha_mode != AlphaMode::Blend {
+                continue;
+            }
+            let Some(mesh) = render_meshes.get(mesh_handle) else {
+                continue;
+            };
+
+            let mesh_key = oit_mesh_key(mesh, view_key);
+            let key = OitStandardMaterialKey {
+                oit_key: OitKey {
+                    mesh_key,
+                    method: oit_camera.method,
+                    layers: oit_camera.layers,
+                    tail_blend: oit_camera.tail_blend,
+                    k_buffer: oit_camera.k_buffer,
+                },
+                bind_group_data: material.key.clone(),
+            };
+            let Ok(pipeline) = pipelines.specialize(&pipeline_cache, &pipeline, key, &mesh.layout)
+            else {
+                continue;
+            };
+
+            oit_phase.add(OitPhaseItem {
+                entity,
+                pipeline,
+                draw_function,
+                distance: inv_view_row_2.dot(mesh_uniform.transform.col(3)),
+            });
+        }
+    }
+}
+
+/// Bevy queues every blended [`StandardMaterial`] in the [`Transparent3d`] phase so anything
+/// that is already rendered with OIT needs to be removed from it.
+fn remove_oit_transparent_items(
+    mut views: Query<(
+        &OitCamera,
+        &RenderPhase<OitPhaseItem>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
+) {
+    for (oit_camera, oit_phase, mut transparent_phase) in &mut views {
+        if !oit_camera.standard_materials || oit_phase.items.is_empty() {
+            continue;
+        }
+
+        let oit_entities = oit_phase
+            .items
+            .iter()
+            .map(|item| item.entity)
+            .collect::<HashSet<_>>();
+        transparent_phase
+            .items
+            .retain(|item| !oit_entities.contains(&item.entity));
+    }
+}
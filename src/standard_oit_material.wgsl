@@ -0,0 +1,122 @@
+#import bevy_oit::oit_draw oit_draw, OitFragmentOutput
+#import bevy_pbr::mesh_view_bindings view
+#import bevy_pbr::mesh_vertex_output MeshVertexOutput
+#import bevy_core_pipeline::tonemapping screen_space_dither, powsafe, tone_mapping
+
+#ifdef OIT_SHADING_PBR
+#import bevy_pbr::pbr_functions as pbr_functions
+#import bevy_pbr::mesh_bindings mesh
+#endif
+
+struct StandardOitMaterial {
+    base_color: vec4<f32>,
+    emissive: vec4<f32>,
+    alpha_cutoff: f32,
+};
+@group(1) @binding(0)
+var<uniform> material: StandardOitMaterial;
+@group(1) @binding(1)
+var base_color_texture: texture_2d<f32>;
+@group(1) @binding(2)
+var base_color_sampler: sampler;
+@group(1) @binding(3)
+var emissive_texture: texture_2d<f32>;
+@group(1) @binding(4)
+var emissive_sampler: sampler;
+
+@fragment
+fn fragment(
+    @builtin(sample_mask) sample_mask: u32,
+    @builtin(front_facing) is_front: bool,
+    in: MeshVertexOutput,
+) -> OitFragmentOutput {
+    var base_color = material.base_color;
+    var emissive = material.emissive.rgb;
+#ifdef VERTEX_COLORS
+    base_color *= in.color;
+#endif
+    // The textures need UVs, they are sampled before any fragment gets discarded
+#ifdef VERTEX_UVS
+#ifdef OIT_BASE_COLOR_TEXTURE
+    base_color *= textureSample(base_color_texture, base_color_sampler, in.uv);
+#endif
+#ifdef OIT_EMISSIVE_TEXTURE
+    emissive *= textureSample(emissive_texture, emissive_sampler, in.uv).rgb;
+#endif
+#endif
+
+#ifdef OIT_ALPHA_MASK
+    if base_color.a < material.alpha_cutoff {
+        discard;
+    }
+#endif
+
+#ifdef OIT_SHADING_UNLIT
+    var color = vec4(base_color.rgb + emissive, base_color.a);
+#endif
+#ifdef OIT_SHADING_GOOCH
+    var color = gooch_shading(
+        base_color,
+        in.world_normal,
+        view.world_position,
+    );
+    color = vec4(color.rgb + emissive, color.a);
+#endif
+#ifdef OIT_SHADING_PBR
+    var color = pbr_shading(base_color, emissive, in, is_front);
+#endif
+
+#ifdef TONEMAP_IN_SHADER
+    color = tone_mapping(color, view.color_grading);
+#ifdef DEBAND_DITHER
+    var color_rgb = powsafe(color.rgb, 1.0 / 2.2);
+    color_rgb = color_rgb + screen_space_dither(in.position.xy);
+    color = vec4(powsafe(color_rgb, 2.2), color.a);
+#endif
+#endif
+
+    return oit_draw(in.position, color, sample_mask);
+}
+
+#ifdef OIT_SHADING_PBR
+// Lights the mesh like a StandardMaterial using the default material properties
+fn pbr_shading(color: vec4<f32>, emissive: vec3<f32>, in: MeshVertexOutput, is_front: bool) -> vec4<f32> {
+    var pbr_input = pbr_functions::pbr_input_new();
+    pbr_input.material.base_color = color;
+    pbr_input.material.emissive = vec4(emissive, 1.0);
+    pbr_input.frag_coord = in.position;
+    pbr_input.world_position = in.world_position;
+    pbr_input.world_normal = pbr_functions::prepare_world_normal(in.world_normal, false, is_front);
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+    pbr_input.N = normalize(pbr_input.world_normal);
+    pbr_input.V = pbr_functions::calculate_view(in.world_position, pbr_input.is_orthographic);
+    pbr_input.flags = mesh.flags;
+    return pbr_functions::pbr(pbr_input);
+}
+#endif
+
+// Interpolates between a warm color and a cooler color based on the angle
+// between the normal and the light.
+fn gooch_shading(color: vec4<f32>, world_normal: vec3<f32>, camera_position: vec3<f32>) -> vec4<f32> {
+    let light_direction = normalize(vec3(-1.0, 2.0, 1.0));
+    let camera_direction = normalize(camera_position);
+
+    let warm = vec3(0.4, 0.4, 0.0);
+    let cool = vec3(0.0, 0.0, 0.4);
+
+    let a = 0.2;
+    let b = 0.8;
+
+    // diffuse
+    let gooch = dot(normalize(world_normal), light_direction) * 0.5 + 0.5;
+    var gooch_color = gooch  * (warm + b * color.rgb) +
+        (1.0 - gooch) * (cool + a * color.rgb);
+
+    // specular
+    let R = reflect(-light_direction, normalize(world_normal));
+    let ER = clamp(dot(camera_direction, normalize(R)), 0.0, 1.0);
+    let specular_strength = pow(ER, 2.0);
+    let spec = gooch_color * specular_strength;
+
+    return vec4(gooch_color.rgb + spec, color.a);
+}
@@ -0,0 +1,66 @@
+struct StandardOitMaterial {
+    base_color: vec4<f32>,
+    emissive: vec4<f32>,
+    alpha_cutoff: f32,
+};
+@group(1) @binding(0)
+var<uniform> material: StandardOitMaterial;
+@group(1) @binding(1)
+var base_color_texture: texture_2d<f32>;
+@group(1) @binding(2)
+var base_color_sampler: sampler;
+
+// Matches the output of the vertex shader of the prepass
+struct FragmentInput {
+    @builtin(position) position: vec4<f32>,
+#ifdef VERTEX_UVS
+    @location(0) uv: vec2<f32>,
+#endif
+#ifdef DEPTH_CLAMP_ORTHO
+    @location(5) clip_position_unclamped: vec4<f32>,
+#endif
+};
+
+#ifdef DEPTH_CLAMP_ORTHO
+struct FragmentOutput {
+    @builtin(frag_depth) frag_depth: f32,
+};
+#endif
+
+// Interleaved gradient noise, from Jorge Jimenez's "Next Generation Post Processing in Call of Duty:
+// Advanced Warfare"
+fn interleaved_gradient_noise(pixel: vec2<f32>) -> f32 {
+    return fract(52.9829189 * fract(dot(pixel, vec2(0.06711056, 0.00583715))));
+}
+
+@fragment
+#ifdef DEPTH_CLAMP_ORTHO
+fn fragment(in: FragmentInput) -> FragmentOutput {
+#else
+fn fragment(in: FragmentInput) {
+#endif
+    var alpha = material.base_color.a;
+#ifdef VERTEX_UVS
+#ifdef OIT_BASE_COLOR_TEXTURE
+    alpha *= textureSample(base_color_texture, base_color_sampler, in.uv).a;
+#endif
+#endif
+
+#ifdef OIT_ALPHA_MASK
+    if alpha < material.alpha_cutoff {
+        discard;
+    }
+#endif
+
+    // Each texel of the shadow map is kept with a probability equal to the alpha, the filtering
+    // of the shadow map then averages the texels into a partial shadow
+    if alpha <= interleaved_gradient_noise(floor(in.position.xy)) {
+        discard;
+    }
+
+#ifdef DEPTH_CLAMP_ORTHO
+    var out: FragmentOutput;
+    out.frag_depth = in.clip_position_unclamped.z;
+    return out;
+#endif
+}
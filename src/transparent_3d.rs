@@ -0,0 +1,154 @@
+use std::ops::Range;
+
+use bevy::{
+    core_pipeline::core_3d::Transparent3d,
+    prelude::*,
+    render::{
+        render_phase::RenderPhase,
+        render_resource::{BindGroup, DynamicUniformBuffer, ShaderType},
+        renderer::{RenderDevice, RenderQueue},
+        view::ExtractedView,
+    },
+    utils::FloatOrd,
+};
+
+use crate::{
+    pipeline::OitRenderPipeline,
+    utils::{BindingResouceExt, RenderDeviceExt},
+    OitCamera, OitDebugView, OitLayersBindGroup, OitPhaseItem,
+};
+
+/// The depths of the fragments blended by a resolve pass.
+///
+/// The depths are the ones stored in the layers so bigger values are closer.
+#[derive(ShaderType, Clone, Copy)]
+pub struct OitDepthSlice {
+    /// The fragments need to be closer than this depth.
+    pub far: f32,
+    /// The fragments can't be closer than this depth.
+    pub near: f32,
+    /// Whether this is the last resolve pass of the frame, it's the one clearing the layers.
+    pub last: u32,
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct OitDepthSliceUniforms(pub DynamicUniformBuffer<OitDepthSlice>);
+
+#[derive(Resource, Deref)]
+pub struct OitDepthSliceBindGroup(pub BindGroup);
+
+/// A resolve pass and the [`Transparent3d`] items drawn right after it.
+pub struct OitResolveSlice {
+    /// The offset of the [`OitDepthSlice`] of the pass in [`OitDepthSliceUniforms`].
+    pub offset: u32,
+    /// The items of [`OitResolveSlices::transparent_phase`] in front of the resolved fragments.
+    pub items: Range<usize>,
+}
+
+/// The resolve passes of a view using the OIT layers, from back to front.
+///
+/// With [`OitCamera::merge_transparent_3d`] the items of bevy's [`Transparent3d`] phase are moved
+/// here and drawn between the passes, so they get blended at their depth.
+#[derive(Component)]
+pub struct OitResolveSlices {
+    pub slices: Vec<OitResolveSlice>,
+    pub transparent_phase: RenderPhase<Transparent3d>,
+}
+
+/// Splits the resolve of the OIT layers at the depth of the [`Transparent3d`] items.
+///
+/// The items are sorted by the view space depth of their origin, so this has the same precision
+/// as the sorting of bevy's transparent phase. Items with the same depth share a slice. The 2d
+/// views don't have a [`Transparent3d`] phase so they get a single slice.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_resolve_slices(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    render_pipeline: Option<Res<OitRenderPipeline>>,
+    mut uniforms: ResMut<OitDepthSliceUniforms>,
+    mut views: Query<
+        (
+            Entity,
+            &ExtractedView,
+            &OitCamera,
+            Option<&RenderPhase<OitPhaseItem>>,
+            Option<&mut RenderPhase<Transparent3d>>,
+        ),
+        With<OitLayersBindGroup>,
+    >,
+) {
+    // Only created when the device supports the storage buffers
+    let Some(render_pipeline) = render_pipeline else {
+        return;
+    };
+
+    uniforms.clear();
+    for (entity, view, oit_camera, oit_phase, transparent_phase) in &mut views {
+        if !oit_camera.method.uses_layers() {
+            continue;
+        }
+
+        // The node doesn't run without OIT items so bevy's pass has to draw them, and the debug
+        // views replace the colors of every fragment
+        let merge = oit_camera.merge_transparent_3d
+            && oit_camera.debug_view == OitDebugView::None
+            && oit_phase.is_some_and(|oit_phase| !oit_phase.items.is_empty());
+        let mut taken = RenderPhase::<Transparent3d>::default();
+        if let Some(mut transparent_phase) = transparent_phase.filter(|_| merge) {
+            taken.items = std::mem::take(&mut transparent_phase.items);
+        }
+
+        let mut slices = Vec::new();
+        let mut far = f32::MIN;
+        let mut start = 0;
+        while start < taken.items.len() {
+            let distance = taken.items[start].distance;
+            let end = start
+                + taken.items[start..]
+                    .iter()
+                    .take_while(|item| FloatOrd(item.distance) == FloatOrd(distance))
+                    .count();
+            let near = view_z_to_depth(view.projection, distance);
+            slices.push(OitResolveSlice {
+                offset: uniforms.push(OitDepthSlice { far, near, last: 0 }),
+                items: start..end,
+            });
+            far = near;
+            start = end;
+        }
+        slices.push(OitResolveSlice {
+            offset: uniforms.push(OitDepthSlice {
+                far,
+                near: f32::MAX,
+                last: 1,
+            }),
+            items: start..start,
+        });
+
+        commands.entity(entity).insert(OitResolveSlices {
+            slices,
+            transparent_phase: taken,
+        });
+    }
+    uniforms.write_buffer(&render_device, &render_queue);
+
+    if uniforms.buffer().is_some() {
+        commands.insert_resource(OitDepthSliceBindGroup(render_device.create_bind_group_ext(
+            "oit_depth_slice_bind_group",
+            &render_pipeline.depth_slice_bind_group_layout,
+            [uniforms.bind()],
+        )));
+    }
+}
+
+/// Returns the depth of the fragments at the view space `z`
+fn view_z_to_depth(projection: Mat4, z: f32) -> f32 {
+    let clip = projection * Vec4::new(0.0, 0.0, z, 1.0);
+    // Items at or behind the camera, like the gizmos, are in front of every fragment
+    if clip.w <= 0.0 {
+        f32::MAX
+    } else {
+        clip.z / clip.w
+    }
+}
@@ -5,7 +5,7 @@ use bevy::{
         render_resource::{
             encase::private::WriteInto, BindGroup, BindGroupDescriptor, BindGroupEntry,
             BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource,
-            BindingType, BlendState, BufferBinding, ColorTargetState, ColorWrites,
+            BindingType, BlendState, Buffer, BufferBinding, ColorTargetState, ColorWrites,
             DepthStencilState, DynamicUniformBuffer, FragmentState, MultisampleState,
             PrimitiveState, RenderPipelineDescriptor, ShaderDefVal, ShaderStages, ShaderType,
             StorageBuffer, TextureFormat, TextureView, UniformBuffer, VertexBufferLayout,
@@ -156,6 +156,22 @@ impl<T: ShaderType + WriteInto> BindingResouceExt for StorageBuffer<T> {
         self.bind_at(u32::MAX)
     }
 }
+impl BindingResouceExt for Buffer {
+    #[inline]
+    #[track_caller]
+    fn bind_at(&self, binding_index: u32) -> BindGroupEntry {
+        BindGroupEntry {
+            binding: binding_index,
+            resource: self.as_entire_binding(),
+        }
+    }
+
+    #[inline]
+    #[track_caller]
+    fn bind(&self) -> BindGroupEntry {
+        self.bind_at(u32::MAX)
+    }
+}
 impl BindingResouceExt for TextureView {
     #[inline]
     #[track_caller]
@@ -0,0 +1,250 @@
+use bevy::{
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_resource::{
+            BindGroup, BindGroupLayout, BlendComponent, BlendFactor, BlendOperation, BlendState,
+            CachedRenderPipelineId, ColorTargetState, ColorWrites, Extent3d, MultisampleState,
+            PipelineCache, RenderPipelineDescriptor, ShaderDefVal, ShaderStages,
+            SpecializedRenderPipeline, SpecializedRenderPipelines, TextureDescriptor,
+            TextureDimension, TextureFormat, TextureSampleType, TextureUsages,
+        },
+        renderer::RenderDevice,
+        texture::{BevyDefault, CachedTexture, TextureCache},
+        view::{ExtractedView, ViewTarget},
+    },
+};
+
+use crate::{
+    utils::{
+        bind_group_layout_types::texture_2d, BindingResouceExt, RenderDeviceExt,
+        RenderPipelineDescriptorBuilder,
+    },
+    OitCamera, OitMethod, OIT_WEIGHTED_BLENDED_COMPOSITE_SHADER_HANDLE,
+};
+
+/// Stores the sum of the weighted premultiplied colors and the sum of the weighted alphas
+pub const ACCUMULATION_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+/// Stores the product of `1 - alpha` of every fragment
+pub const REVEALAGE_TEXTURE_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+
+/// The color targets of the OIT draw pass when using [`OitMethod::WeightedBlended`]
+pub(crate) fn weighted_blended_targets() -> Vec<Option<ColorTargetState>> {
+    vec![
+        Some(ColorTargetState {
+            format: ACCUMULATION_TEXTURE_FORMAT,
+            blend: Some(BlendState {
+                color: BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+                alpha: BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+            }),
+            write_mask: ColorWrites::ALL,
+        }),
+        Some(ColorTargetState {
+            format: REVEALAGE_TEXTURE_FORMAT,
+            blend: Some(BlendState {
+                color: BlendComponent {
+                    src_factor: BlendFactor::Zero,
+                    dst_factor: BlendFactor::OneMinusSrc,
+                    operation: BlendOperation::Add,
+                },
+                alpha: BlendComponent {
+                    src_factor: BlendFactor::Zero,
+                    dst_factor: BlendFactor::OneMinusSrc,
+                    operation: BlendOperation::Add,
+                },
+            }),
+            write_mask: ColorWrites::ALL,
+        }),
+    ]
+}
+
+/// The render targets used by [`OitMethod::WeightedBlended`]
+#[derive(Component)]
+pub struct OitWeightedBlendedTextures {
+    pub accumulation: CachedTexture,
+    pub revealage: CachedTexture,
+}
+
+pub fn prepare_weighted_blended_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    views: Query<(Entity, &ExtractedCamera, &OitCamera)>,
+    msaa: Res<Msaa>,
+) {
+    for (entity, camera, oit_camera) in &views {
+        if oit_camera.method != OitMethod::WeightedBlended {
+            continue;
+        }
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+
+        let mut descriptor = TextureDescriptor {
+            label: Some("oit_accumulation_texture"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: msaa.samples(),
+            dimension: TextureDimension::D2,
+            format: ACCUMULATION_TEXTURE_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        };
+        let accumulation = texture_cache.get(&render_device, descriptor.clone());
+
+        descriptor.label = Some("oit_revealage_texture");
+        descriptor.format = REVEALAGE_TEXTURE_FORMAT;
+        let revealage = texture_cache.get(&render_device, descriptor);
+
+        commands.entity(entity).insert(OitWeightedBlendedTextures {
+            accumulation,
+            revealage,
+        });
+    }
+}
+
+#[derive(Component, Deref)]
+pub struct OitWeightedBlendedBindGroup(pub BindGroup);
+
+pub fn queue_weighted_blended_bind_groups(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    composite_pipeline: Res<OitCompositePipeline>,
+    views: Query<(Entity, &OitWeightedBlendedTextures)>,
+    msaa: Res<Msaa>,
+) {
+    let layout = match msaa.samples() {
+        1 => &composite_pipeline.layout,
+        _ => &composite_pipeline.layout_multisampled,
+    };
+
+    for (entity, textures) in &views {
+        let bind_group = render_device.create_bind_group_ext(
+            "oit_weighted_blended_bind_group",
+            layout,
+            [
+                textures.accumulation.default_view.bind(),
+                textures.revealage.default_view.bind(),
+            ],
+        );
+        commands
+            .entity(entity)
+            .insert(OitWeightedBlendedBindGroup(bind_group));
+    }
+}
+
+/// Composites the accumulation and revealage textures on the view target
+#[derive(Resource)]
+pub struct OitCompositePipeline {
+    layout: BindGroupLayout,
+    layout_multisampled: BindGroupLayout,
+}
+
+impl FromWorld for OitCompositePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let sample_type = TextureSampleType::Float { filterable: false };
+        let layout = render_device.create_bind_group_layout_ext(
+            "oit_weighted_blended_layout",
+            ShaderStages::FRAGMENT,
+            [
+                texture_2d(sample_type, false),
+                texture_2d(sample_type, false),
+            ],
+        );
+        let layout_multisampled = render_device.create_bind_group_layout_ext(
+            "oit_weighted_blended_layout_multisampled",
+            ShaderStages::FRAGMENT,
+            [texture_2d(sample_type, true), texture_2d(sample_type, true)],
+        );
+        OitCompositePipeline {
+            layout,
+            layout_multisampled,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OitCompositeKey {
+    pub msaa_samples: u32,
+    pub hdr: bool,
+}
+
+impl SpecializedRenderPipeline for OitCompositePipeline {
+    type Key = OitCompositeKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let layout = match key.msaa_samples {
+            1 => self.layout.clone(),
+            _ => self.layout_multisampled.clone(),
+        };
+        // naga_oil's #ifdef only checks if the def exists
+        let mut shader_defs: Vec<ShaderDefVal> = Vec::new();
+        if key.msaa_samples > 1 {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+
+        RenderPipelineDescriptorBuilder::fullscreen()
+            .label("oit_composite_pipeline")
+            .fragment(
+                OIT_WEIGHTED_BLENDED_COMPOSITE_SHADER_HANDLE.typed(),
+                "fragment",
+                &[ColorTargetState {
+                    format: if key.hdr {
+                        ViewTarget::TEXTURE_FORMAT_HDR
+                    } else {
+                        TextureFormat::bevy_default()
+                    },
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                }],
+                &shader_defs,
+            )
+            .multisample_state(MultisampleState {
+                count: key.msaa_samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            })
+            .layout(vec![layout])
+            .build()
+    }
+}
+
+#[derive(Component, Deref)]
+pub struct OitCompositePipelineId(pub CachedRenderPipelineId);
+
+pub fn queue_composite_pipeline(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    composite_pipeline: Res<OitCompositePipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<OitCompositePipeline>>,
+    views: Query<(Entity, &ExtractedView, &OitCamera)>,
+    msaa: Res<Msaa>,
+) {
+    for (entity, view, oit_camera) in &views {
+        if oit_camera.method != OitMethod::WeightedBlended {
+            continue;
+        }
+
+        let key = OitCompositeKey {
+            msaa_samples: msaa.samples(),
+            hdr: view.hdr,
+        };
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &composite_pipeline, key);
+        commands
+            .entity(entity)
+            .insert(OitCompositePipelineId(pipeline_id));
+    }
+}
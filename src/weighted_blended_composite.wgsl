@@ -0,0 +1,43 @@
+#ifdef MULTISAMPLED
+@group(0) @binding(0)
+var accumulation_texture: texture_multisampled_2d<f32>;
+@group(0) @binding(1)
+var revealage_texture: texture_multisampled_2d<f32>;
+#else
+@group(0) @binding(0)
+var accumulation_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var revealage_texture: texture_2d<f32>;
+#endif
+
+struct FullscreenVertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@fragment
+fn fragment(
+    in: FullscreenVertexOutput,
+#ifdef MULTISAMPLED
+    @builtin(sample_index) sample_index: u32,
+#endif
+) -> @location(0) vec4<f32> {
+    let coords = vec2<i32>(floor(in.position.xy));
+#ifdef MULTISAMPLED
+    let accumulation = textureLoad(accumulation_texture, coords, i32(sample_index));
+    let revealage = textureLoad(revealage_texture, coords, i32(sample_index)).r;
+#else
+    let accumulation = textureLoad(accumulation_texture, coords, 0);
+    let revealage = textureLoad(revealage_texture, coords, 0).r;
+#endif
+
+    // Nothing was drawn on this pixel
+    if revealage >= 1.0 {
+        discard;
+    }
+
+    // The view target is blended with the alpha so the average color gets multiplied by the
+    // coverage (1 - revealage) and the background by the revealage
+    let average_color = accumulation.rgb / max(accumulation.a, 1e-5);
+    return vec4(average_color, 1.0 - revealage);
+}